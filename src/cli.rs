@@ -1,19 +1,42 @@
-use std::{net::IpAddr, path::PathBuf};
+use std::{
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  time::Duration,
+};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
-use crate::config::Mode;
+use crate::config::{ArchiveFormat, Mode, ProxyAddr, ProxyToken, Rate};
 
 #[derive(Parser)]
 #[command(name = "Net Copy", author, version, about, long_about = None)]
 pub struct Cli {
-  /// The files to be sent, empty means serve as receiver
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
+  /// The files to be sent, `-` streams stdin, empty means serve as receiver
   pub files: Vec<PathBuf>,
 
-  /// The host ip for the server
+  /// The file name of the data sent from stdin
+  #[clap(long, value_parser = parse_file_name, value_name = "NAME", default_value = "stdin")]
+  pub name: String,
+
+  /// Write a single received upload to stdout and exit
+  #[clap(long, value_parser, conflicts_with_all = ["files", "secure"])]
+  pub stdout: bool,
+
+  /// The host ip for the server, skips the interface prompt
   #[clap(short = 'l', long, value_parser)]
   pub host: Option<IpAddr>,
 
+  /// Serve on the first address of this network interface
+  #[clap(short = 'i', long, value_parser, value_name = "NAME", conflicts_with = "host")]
+  pub interface: Option<String>,
+
+  /// Listen on this address and print a URL per reachable address, `::` covers IPv4 and IPv6
+  #[clap(short = 'b', long, value_parser, value_name = "IP", conflicts_with_all = ["host", "interface"])]
+  pub bind: Option<IpAddr>,
+
   /// The port for the server
   #[clap(short = 'p', long, value_parser)]
   pub port: Option<u16>,
@@ -26,18 +49,78 @@ pub struct Cli {
   #[clap(short = 'r', long, value_parser)]
   pub reserve: bool,
 
-  /// Proxy for TCP connection
-  #[clap(short = 'x', long, value_parser, action = clap::ArgAction::Append)]
-  pub proxy: Option<Vec<IpAddr>>,
+  /// Proxy for TCP connection, the port of its control listener defaults to 7070 and 7575
+  #[clap(short = 'x', long, value_parser, value_name = "HOST[:PORT]", action = clap::ArgAction::Append)]
+  pub proxy: Option<Vec<ProxyAddr>>,
 
   /// Disable automatically check proxy from gateway
   #[clap(short = 'X', long, value_parser)]
   pub no_proxy: bool,
 
+  /// Authenticate to the proxy with `NAME:SECRET`, the proxy itself accepts every token given
+  #[clap(long, value_parser, value_name = "NAME:SECRET", action = clap::ArgAction::Append)]
+  pub proxy_token: Option<Vec<ProxyToken>>,
+
+  /// The control addresses of the proxy mode, `0.0.0.0:7070` or else `0.0.0.0:7575` by default
+  #[clap(long, value_parser, value_name = "ADDR", action = clap::ArgAction::Append)]
+  pub control_addr: Option<Vec<SocketAddr>>,
+
   /// Serve mode
   #[clap(short = 'm', long, value_enum)]
   pub mode: Option<Mode>,
 
+  /// The directory to save received files into
+  #[clap(short = 'o', long, value_parser, value_name = "DIR", global = true)]
+  pub output_dir: Option<PathBuf>,
+
+  /// The directory to stage temporary files of the sender
+  #[clap(long, value_parser, value_name = "DIR")]
+  pub scratch_dir: Option<PathBuf>,
+
+  /// The archive format of multi-file sends, can be overridden per request with `?format=`
+  #[clap(long, value_enum, value_name = "FORMAT")]
+  pub archive_format: Option<ArchiveFormat>,
+
+  /// Limit the transfer rate of all connections together in bytes per second, e.g. `500K` or `20M`
+  #[clap(long, value_parser, value_name = "RATE")]
+  pub limit_rate: Option<Rate>,
+
+  /// Limit the transfer rate of each connection in bytes per second
+  #[clap(long, value_parser, value_name = "RATE")]
+  pub limit_rate_per_conn: Option<Rate>,
+
+  /// Serve over HTTPS, a self-signed certificate is generated unless --tls-cert is given
+  #[clap(short = 't', long, value_parser)]
+  pub tls: bool,
+
+  /// The PEM certificate chain for HTTPS
+  #[clap(long, value_parser, value_name = "FILE", requires = "tls_key")]
+  pub tls_cert: Option<PathBuf>,
+
+  /// The PEM private key for HTTPS
+  #[clap(long, value_parser, value_name = "FILE", requires = "tls_cert")]
+  pub tls_key: Option<PathBuf>,
+
+  /// Only serve `ncp get`/`ncp put` with a one-time code, files are end-to-end encrypted
+  #[clap(short = 'e', long, value_parser, conflicts_with = "tls")]
+  pub secure: bool,
+
+  /// Stop after the first complete download or received file
+  #[clap(long, value_parser, conflicts_with_all = ["max_downloads", "max_uploads"])]
+  pub once: bool,
+
+  /// Stop after the files have been downloaded this many times
+  #[clap(long, value_parser, value_name = "N")]
+  pub max_downloads: Option<u64>,
+
+  /// Stop after this many files have been received
+  #[clap(long, value_parser, value_name = "N")]
+  pub max_uploads: Option<u64>,
+
+  /// Stop after this long, e.g. `90s`, `10m` or `2h`, the exit code is 2 unless the transfers are done by then
+  #[clap(long, value_parser = parse_duration, value_name = "DURATION")]
+  pub expire: Option<Duration>,
+
   /// Auto rename file if exist
   #[clap(short = 'a', long, value_parser)]
   pub auto_rename: bool,
@@ -46,3 +129,72 @@ pub struct Cli {
   #[clap(short = 's', long, value_parser)]
   pub prompt_save_config: bool,
 }
+
+#[derive(Subcommand)]
+pub enum Command {
+  /// Download files from a running sender
+  Get {
+    /// The URL printed by the sender, or the code of a sender started with --secure
+    #[clap(value_name = "URL|CODE")]
+    target: String,
+
+    #[clap(flatten)]
+    tls: TlsArgs,
+  },
+  /// Upload files or directories to a running receiver
+  Put {
+    /// The URL printed by the receiver, or the code of a receiver started with --secure
+    #[clap(value_name = "URL|CODE")]
+    target: String,
+
+    /// The files to be sent
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    tls: TlsArgs,
+  },
+}
+
+/// Parse a duration of seconds, minutes, hours or days like `90s`, `10m`, `2h` or `1d`, plain numbers are seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+  let value = value.trim();
+  let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+  let number = number
+    .parse::<u64>()
+    .map_err(|_| format!("invalid duration {:?}", value))?;
+  let seconds = match unit {
+    "" | "s" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 24 * 60 * 60,
+    _ => return Err(format!("invalid duration unit {:?}, expected s, m, h or d", unit)),
+  };
+  Ok(Duration::from_secs(number.saturating_mul(seconds)))
+}
+
+/// Parse a plain file name, it is sent as is in `Content-Disposition` and becomes the name of the downloaded file
+fn parse_file_name(value: &str) -> Result<String, String> {
+  if value.trim().is_empty() || value == "." || value == ".." {
+    return Err(format!("invalid file name {:?}", value));
+  }
+  if value.contains(['/', '\\']) {
+    return Err("a file name cannot contain path separators".to_string());
+  }
+  if value.chars().any(|c| c.is_control() || c == '"') {
+    return Err("control character or quote in file name".to_string());
+  }
+  Ok(value.to_string())
+}
+
+/// How `ncp get`/`ncp put` verify an HTTPS server
+#[derive(Args)]
+pub struct TlsArgs {
+  /// The SHA-256 fingerprint of the server certificate
+  #[clap(long, value_parser, value_name = "HEX")]
+  pub fingerprint: Option<String>,
+
+  /// Accept any server certificate
+  #[clap(long, value_parser, conflicts_with = "fingerprint")]
+  pub insecure: bool,
+}
@@ -0,0 +1,602 @@
+use std::{
+  fmt, fs,
+  io::{self, BufRead, Read, Seek, SeekFrom, Write},
+  net::TcpStream,
+  path::{Path, PathBuf},
+  thread,
+  time::Duration,
+};
+
+use bufstream::BufStream;
+use indicatif::ProgressBar;
+use tar::Archive;
+
+use crate::{
+  digest::{Checksum, Digest, HashReader, Hasher},
+  http::{self, ChunkedReader, ChunkedWriter},
+  recv::Recv,
+  secure::{self, Session},
+  send::Send,
+  stream::Stream,
+  tls::Tls,
+};
+
+/// Attempts of each transfer before giving up, interrupted transfers are resumed where possible
+const MAX_ATTEMPTS: u64 = 5;
+
+/// Native counterpart of the sender and the receiver
+pub struct Client {}
+
+/// Why a transfer attempt failed
+enum Failure {
+  /// Retrying won't help, e.g. the request has been rejected
+  Fatal(String),
+  /// Network errors and the like, worth another attempt
+  Retry(String),
+}
+
+impl From<io::Error> for Failure {
+  fn from(e: io::Error) -> Self {
+    Self::Retry(e.to_string())
+  }
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Fatal(e) | Self::Retry(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+/// The server to talk to, parsed from the printed URL
+struct Server {
+  tls: bool,
+  host: String,
+  addr: String,
+  path: String,
+  fingerprint: Option<String>,
+  insecure: bool,
+}
+
+impl Server {
+  fn parse(url: &str, fingerprint: Option<&str>, insecure: bool) -> Option<Self> {
+    let (tls, rest) = match url.trim().strip_prefix("https://") {
+      Some(rest) => (true, rest),
+      None => (false, url.trim().strip_prefix("http://")?),
+    };
+    let (authority, path) = match rest.split_once('/') {
+      Some((authority, path)) => (authority, format!("/{}", path)),
+      None => (rest, "/".to_string()),
+    };
+    let (host, addr) = match authority.rsplit_once(':') {
+      Some((host, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+        (host, authority.to_string())
+      }
+      _ => (authority, format!("{}:{}", authority, if tls { 443 } else { 80 })),
+    };
+    if host.is_empty() {
+      return None;
+    }
+    Some(Self {
+      tls,
+      host: host.to_string(),
+      addr,
+      path,
+      fingerprint: fingerprint.map(str::to_string),
+      insecure,
+    })
+  }
+
+  /// Connect and send the request header lines (without the terminating empty line)
+  fn request(&self, request: &str) -> Result<BufStream<Stream>, Failure> {
+    let stream = TcpStream::connect(&self.addr)
+      .and_then(|stream| stream.set_read_timeout(Some(Duration::from_secs(60))).map(|_| stream))
+      .map_err(|e| Failure::Retry(format!("connect {} failed: {}", self.addr, e)))?;
+    let stream = if self.tls {
+      Tls::connect(stream, &self.host, self.fingerprint.as_deref(), self.insecure).map_err(Failure::Fatal)?
+    } else {
+      Stream::Tcp(stream)
+    };
+    let mut buf_stream = BufStream::new(stream);
+    buf_stream.write_all(format!("{request}Host: {}\r\n\r\n", self.addr).as_bytes())?;
+    buf_stream.flush()?;
+    Ok(buf_stream)
+  }
+}
+
+impl Client {
+  /// Download from a sender, `target` is either the printed URL or the code of a secure sender
+  pub fn get(target: &str, output_dir: PathBuf, fingerprint: Option<&str>, insecure: bool) {
+    if !output_dir.as_os_str().is_empty()
+      && let Err(e) = fs::create_dir_all(&output_dir)
+    {
+      eprintln!("Create output directory {:?} failed: {}", output_dir, e);
+      return;
+    }
+    if !Self::is_url(target) {
+      Self::get_secure(target, &output_dir);
+      return;
+    }
+    let Some(mut server) = Server::parse(target, fingerprint, insecure) else {
+      eprintln!("Bad URL: {}", target);
+      return;
+    };
+    // Archives are extracted as tar whatever format the sender defaults to, single files ignore the parameter
+    if !server.path.contains("format=") {
+      server.path.push_str(if server.path.contains('?') {
+        "&format=tar"
+      } else {
+        "?format=tar"
+      });
+    }
+
+    let mut resume = None;
+    let mut attempt = 1;
+    loop {
+      match Self::try_get(&server, &output_dir, &mut resume) {
+        Ok(true) => return,
+        Ok(false) => continue,
+        Err(Failure::Retry(e)) if attempt < MAX_ATTEMPTS => {
+          eprintln!(
+            "Get {} failed: {}, retrying ({}/{})",
+            target,
+            e,
+            attempt,
+            MAX_ATTEMPTS - 1
+          );
+          thread::sleep(Duration::from_secs(attempt));
+          attempt += 1;
+        }
+        Err(e) => {
+          eprintln!("Get {} failed: {}", target, e);
+          return;
+        }
+      }
+    }
+  }
+
+  /// One attempt of a plain download, `resume` tracks the part file and the ETag of a single file across attempts.
+  /// Returns `false` when the request should be repeated right away, i.e. an earlier part file has been found
+  fn try_get(server: &Server, output_dir: &Path, resume: &mut Option<(PathBuf, String)>) -> Result<bool, Failure> {
+    let offset = match resume {
+      Some((part_path, _)) => fs::metadata(part_path).map(|metadata| metadata.len()).unwrap_or(0),
+      None => 0,
+    };
+    let range = match resume {
+      Some((_, etag)) if offset > 0 => format!("Range: bytes={}-\r\nIf-Range: {}\r\n", offset, etag),
+      _ => String::new(),
+    };
+    let mut buf_stream = server.request(&format!("GET {} HTTP/1.1\r\n{}", server.path, range))?;
+    let headers = Self::read_headers(&mut buf_stream)?;
+    let status = Self::status(&headers);
+    if status != "200" && status != "206" {
+      return Err(Failure::Fatal(headers[0].trim().to_string()));
+    }
+
+    let name = http::header(&headers, "Content-Disposition")
+      .and_then(http::content_disposition_filename)
+      .unwrap_or_else(|| server.path.rsplit('/').next().unwrap_or_default());
+    let file_path = Recv::to_local_path(name, false, output_dir)
+      .map_err(|e| Failure::Fatal(format!("rejected file name {:?}: {}", name, e)))?;
+    let content_length = http::header(&headers, "Content-Length").and_then(|value| value.parse::<u64>().ok());
+    let is_chunked = http::is_chunked(&headers);
+    let is_archive = http::header(&headers, "Archive-Format") == Some("tar");
+    if is_archive {
+      eprintln!("Extracting {:?} into {:?}", name, output_dir);
+      return Self::get_archive(buf_stream, is_chunked, content_length, output_dir).map(|_| true);
+    }
+
+    // A body of unknown length, e.g. stdin, is streamed into the part file from the start as it cannot be resumed
+    if content_length.is_none() && !is_chunked {
+      return Err(Failure::Fatal("missing content length".to_string()));
+    }
+    let part_path = Recv::get_part_path(&file_path);
+    let etag = http::header(&headers, "ETag").map(str::to_string);
+    let start = if status == "206" {
+      match http::header(&headers, "Content-Range").and_then(http::parse_content_range) {
+        Some((start, _, _)) if start == offset => start,
+        _ => {
+          *resume = None;
+          return Err(Failure::Retry("unexpected content range".to_string()));
+        }
+      }
+    } else {
+      let existing = fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0);
+      let accept_ranges = http::header(&headers, "Accept-Ranges") == Some("bytes");
+      if resume.is_none()
+        && content_length.is_some()
+        && existing > 0
+        && accept_ranges
+        && let Some(etag) = etag
+      {
+        eprintln!("Resuming {:?} from byte {}", file_path, existing);
+        *resume = Some((part_path, etag));
+        return Ok(false);
+      }
+      0
+    };
+    *resume = etag
+      .filter(|_| content_length.is_some())
+      .map(|etag| (part_path.clone(), etag));
+
+    if let Some(folder) = file_path.parent()
+      && !folder.as_os_str().is_empty()
+    {
+      fs::create_dir_all(folder)?;
+    }
+    let mut file = fs::OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&part_path)?;
+    file.set_len(start)?;
+    file.seek(SeekFrom::End(0))?;
+    eprintln!("Getting {:?}", file_path);
+    let trailers = match content_length {
+      Some(content_length) => {
+        let pb = Recv::progress_bar(start + content_length);
+        pb.set_position(start);
+        let copied = io::copy(
+          &mut (&mut buf_stream).take(content_length),
+          &mut pb.wrap_write(&mut file),
+        )?;
+        pb.finish();
+        file.sync_all()?;
+        if copied < content_length {
+          return Err(Failure::Retry(
+            "connection closed before download completed".to_string(),
+          ));
+        }
+        vec![]
+      }
+      None => {
+        let pb = Recv::spinner();
+        let mut chunked_reader = ChunkedReader::new(&mut buf_stream);
+        io::copy(&mut chunked_reader, &mut pb.wrap_write(&mut file))?;
+        pb.finish();
+        file.sync_all()?;
+        chunked_reader.trailers
+      }
+    };
+
+    let checksums = Checksum::from_headers(&[headers, trailers].concat()).map_err(Failure::Fatal)?;
+    let digest = if checksums.is_empty() {
+      None
+    } else {
+      let mut hasher = Hasher::for_checksums(&checksums);
+      io::copy(&mut fs::File::open(&part_path)?, &mut hasher)?;
+      let digest = hasher.finalize();
+      if let Err(e) = digest.verify(&checksums) {
+        fs::remove_file(&part_path)?;
+        *resume = None;
+        return Err(Failure::Retry(format!("verify {:?} failed: {}", file_path, e)));
+      }
+      Some(digest)
+    };
+    let local_path = Recv::commit_part(&part_path, &file_path, false)?;
+    match digest {
+      Some(digest) => eprintln!("Get {:?} done ({})", local_path, digest),
+      None => eprintln!("Get {:?} done", local_path),
+    }
+    Ok(true)
+  }
+
+  /// Extract an archive response, its digest is verified when sent as trailer
+  fn get_archive(
+    mut buf_stream: BufStream<Stream>, is_chunked: bool, content_length: Option<u64>, output_dir: &Path,
+  ) -> Result<(), Failure> {
+    let pb = Recv::spinner();
+    if !is_chunked {
+      let reader = (&mut buf_stream).take(content_length.unwrap_or(u64::MAX));
+      Self::unpack(pb.wrap_read(reader), output_dir, &pb)?;
+      pb.finish();
+      eprintln!("Get archive done");
+      return Ok(());
+    }
+    let mut chunked_reader = ChunkedReader::new(&mut buf_stream);
+    let mut hash_reader = HashReader::new(pb.wrap_read(&mut chunked_reader));
+    Self::unpack(&mut hash_reader, output_dir, &pb)?;
+    pb.finish();
+    let (_, digest) = hash_reader.finish();
+    let checksums = Checksum::from_headers(&chunked_reader.trailers).map_err(Failure::Fatal)?;
+    digest
+      .verify(&checksums)
+      .map_err(|e| Failure::Retry(format!("verify archive failed: {}", e)))?;
+    eprintln!("Get archive done ({})", digest);
+    Ok(())
+  }
+
+  /// Download the files of a secure sender into `output_dir`
+  fn get_secure(code: &str, output_dir: &Path) {
+    let Some((server, password)) = Self::parse_code(code) else {
+      eprintln!("Bad code: {}", code);
+      return;
+    };
+    let session = match Self::handshake(&server, password) {
+      Ok(session) => session,
+      Err(e) => {
+        eprintln!("Handshake with {} failed: {}", server.addr, e);
+        return;
+      }
+    };
+    let result = server
+      .request(&format!("GET {} HTTP/1.1\r\n{}", server.path, session.to_headers()))
+      .and_then(|mut buf_stream| {
+        let headers = Self::read_headers(&mut buf_stream)?;
+        if Self::status(&headers) != "200" {
+          return Err(Failure::Fatal(headers[0].trim().to_string()));
+        }
+        let pb = Recv::spinner();
+        Self::unpack(pb.wrap_read(session.keys.reader(&mut buf_stream)), output_dir, &pb)?;
+        pb.finish();
+        Ok(())
+      });
+    match result {
+      Ok(_) => eprintln!("Get files from {} done", server.addr),
+      Err(e) => eprintln!("Get files from {} failed: {}", server.addr, e),
+    }
+  }
+
+  /// Upload files and directory trees to a receiver, `target` is either the printed URL or the code of a secure
+  /// receiver
+  pub fn put(target: &str, file_paths: &[PathBuf], fingerprint: Option<&str>, insecure: bool) {
+    if !Self::is_url(target) {
+      Self::put_secure(target, file_paths);
+      return;
+    }
+    let Some(server) = Server::parse(target, fingerprint, insecure) else {
+      eprintln!("Bad URL: {}", target);
+      return;
+    };
+    let mut files = vec![];
+    for file_path in file_paths {
+      if let Err(e) = Self::collect_files(file_path, Send::archive_name(file_path), &mut files) {
+        eprintln!("Read {:?} failed: {}", file_path, e);
+        return;
+      }
+    }
+
+    for (file_path, remote_path) in &files {
+      let digest = match fs::File::open(file_path).and_then(|mut file| {
+        let mut hasher = Hasher::new();
+        io::copy(&mut file, &mut hasher)?;
+        Ok(hasher.finalize())
+      }) {
+        Ok(digest) => digest,
+        Err(e) => {
+          eprintln!("Read {:?} failed: {}", file_path, e);
+          return;
+        }
+      };
+      let mut attempt = 1;
+      loop {
+        match Self::try_put(&server, file_path, remote_path, &digest) {
+          Ok(_) => {
+            eprintln!("Put {:?} done ({})", file_path, digest);
+            break;
+          }
+          Err(Failure::Retry(e)) if attempt < MAX_ATTEMPTS => {
+            eprintln!(
+              "Put {:?} failed: {}, retrying ({}/{})",
+              file_path,
+              e,
+              attempt,
+              MAX_ATTEMPTS - 1
+            );
+            thread::sleep(Duration::from_secs(attempt));
+            attempt += 1;
+          }
+          Err(e) => {
+            eprintln!("Put {:?} failed: {}", file_path, e);
+            return;
+          }
+        }
+      }
+    }
+  }
+
+  /// List the regular files beneath `file_path` along with their `File-Path` on the receiver
+  fn collect_files(file_path: &Path, remote_path: &Path, files: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+    if !file_path.is_dir() {
+      let remote_path = remote_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+      files.push((file_path.to_path_buf(), remote_path));
+      return Ok(());
+    }
+    let mut entries = fs::read_dir(file_path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+      // Symbolic links to directories are not followed, so that loops are impossible
+      if entry.file_type()?.is_symlink() && entry.path().is_dir() {
+        continue;
+      }
+      Self::collect_files(&entry.path(), &remote_path.join(entry.file_name()), files)?;
+    }
+    Ok(())
+  }
+
+  /// One attempt of a plain upload, the receiver is asked for the size of an interrupted upload first
+  fn try_put(server: &Server, file_path: &Path, remote_path: &str, digest: &Digest) -> Result<(), Failure> {
+    let mut file = fs::File::open(file_path)?;
+    let size = file.metadata()?.len();
+    let mut buf_stream = server.request(&format!(
+      "HEAD {} HTTP/1.1\r\nFile-Path: {}\r\n",
+      server.path, remote_path
+    ))?;
+    let headers = Self::read_headers(&mut buf_stream)?;
+    if Self::status(&headers) != "200" {
+      return Err(Failure::Fatal(headers[0].trim().to_string()));
+    }
+    let offset = http::header(&headers, "Upload-Offset")
+      .and_then(|value| value.parse::<u64>().ok())
+      .filter(|offset| *offset < size)
+      .unwrap_or(0);
+
+    let content_range = if offset > 0 {
+      eprintln!("Resuming {:?} from byte {}", file_path, offset);
+      format!("Content-Range: bytes {}-{}/{}\r\n", offset, size - 1, size)
+    } else {
+      eprintln!("Putting {:?}", file_path);
+      String::new()
+    };
+    let mut buf_stream = server.request(&format!(
+      "POST {} HTTP/1.1\r\n\
+      File-Path: {}\r\n\
+      Content-Type: application/octet-stream\r\n\
+      Content-Length: {}\r\n\
+      {}\
+      {}\
+      Expect: 100-continue\r\n",
+      server.path,
+      remote_path,
+      size - offset,
+      content_range,
+      digest.to_headers()
+    ))?;
+    let headers = Self::read_headers(&mut buf_stream)?;
+    match Self::status(&headers) {
+      "100" => {}
+      "416" => return Err(Failure::Retry(headers[0].trim().to_string())),
+      _ => return Err(Failure::Fatal(headers[0].trim().to_string())),
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let pb = Recv::progress_bar(size);
+    pb.set_position(offset);
+    io::copy(&mut file.take(size - offset), &mut pb.wrap_write(&mut buf_stream))?;
+    buf_stream.flush()?;
+    pb.finish();
+    let headers = Self::read_headers(&mut buf_stream)?;
+    match Self::status(&headers) {
+      "200" => Ok(()),
+      // The receiver has dropped the corrupted upload, start over
+      "422" => Err(Failure::Retry(headers[0].trim().to_string())),
+      _ => Err(Failure::Fatal(headers[0].trim().to_string())),
+    }
+  }
+
+  /// Upload `file_paths` to a secure receiver
+  fn put_secure(code: &str, file_paths: &[PathBuf]) {
+    let Some((server, password)) = Self::parse_code(code) else {
+      eprintln!("Bad code: {}", code);
+      return;
+    };
+    let session = match Self::handshake(&server, password) {
+      Ok(session) => session,
+      Err(e) => {
+        eprintln!("Handshake with {} failed: {}", server.addr, e);
+        return;
+      }
+    };
+    let result = server
+      .request(&format!(
+        "POST {} HTTP/1.1\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Expect: 100-continue\r\n\
+        {}",
+        server.path,
+        session.to_headers()
+      ))
+      .and_then(|mut buf_stream| {
+        let headers = Self::read_headers(&mut buf_stream)?;
+        if Self::status(&headers) != "100" {
+          return Err(Failure::Fatal(headers[0].trim().to_string()));
+        }
+        // The tar is streamed as it is built, its size is not known up front
+        let pb = Recv::spinner();
+        Send::tar(
+          session.keys.writer(ChunkedWriter::new(pb.wrap_write(&mut buf_stream))),
+          file_paths,
+        )
+        .and_then(|writer| writer.finish())
+        .and_then(|writer| writer.finish())?;
+        pb.finish();
+        let headers = Self::read_headers(&mut buf_stream)?;
+        if Self::status(&headers) != "200" {
+          return Err(Failure::Fatal(headers[0].trim().to_string()));
+        }
+        Ok(())
+      });
+    match result {
+      Ok(_) => eprintln!("Put files to {} done", server.addr),
+      Err(e) => eprintln!("Put files to {} failed: {}", server.addr, e),
+    }
+  }
+
+  fn is_url(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://")
+  }
+
+  /// Split a secure code into the server and the password
+  fn parse_code(code: &str) -> Option<(Server, &str)> {
+    let (addr, key, password) = secure::parse_code(code)?;
+    let server = Server::parse(&format!("http://{}/{}", addr, key), None, false)?;
+    Some((server, password))
+  }
+
+  /// Run the SPAKE2 handshake with the server, fails if the password doesn't match
+  fn handshake(server: &Server, password: &str) -> Result<Session, Failure> {
+    let (state, message) = secure::start(password);
+    let mut buf_stream = server.request(&format!(
+      "POST {}/pake HTTP/1.1\r\nNcp-Pake: {}\r\nContent-Length: 0\r\n",
+      server.path, message
+    ))?;
+    let headers = Self::read_headers(&mut buf_stream)?;
+    if Self::status(&headers) != "200" {
+      return Err(Failure::Fatal(headers[0].trim().to_string()));
+    }
+    secure::finish(state, &headers).map_err(Failure::Fatal)
+  }
+
+  fn read_headers<S: Read + Write>(buf_stream: &mut BufStream<S>) -> io::Result<Vec<String>> {
+    let mut headers = vec![];
+    loop {
+      let mut line = String::new();
+      if buf_stream.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+      }
+      if line == "\r\n" {
+        break;
+      }
+      headers.push(line);
+      if headers.len() > 100 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many response headers"));
+      }
+    }
+    if headers.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "empty response headers"));
+    }
+    Ok(headers)
+  }
+
+  fn status(headers: &[String]) -> &str {
+    headers[0].split_whitespace().nth(1).unwrap_or_default()
+  }
+
+  /// Extract the tar stream beneath `output_dir`, entries that would escape from it are skipped
+  fn unpack<R: Read>(reader: R, output_dir: &Path, pb: &ProgressBar) -> io::Result<()> {
+    let output_dir = if output_dir.as_os_str().is_empty() {
+      Path::new(".")
+    } else {
+      output_dir
+    };
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+      let mut entry = entry?;
+      let path = entry.path()?.to_path_buf();
+      if entry.unpack_in(output_dir)? {
+        pb.println(path.display().to_string());
+      } else {
+        pb.println(format!("Skipped {:?} outside the output directory", path));
+      }
+    }
+    // Read up to the end, so that a truncated stream is reported
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+  }
+}
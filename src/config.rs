@@ -1,8 +1,9 @@
 use std::{
   env,
+  fmt::Display,
   fs::{File, create_dir_all},
   io::{Read, Write},
-  net::IpAddr,
+  net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
   path::PathBuf,
   str::FromStr,
 };
@@ -18,15 +19,251 @@ pub enum Mode {
   Proxy,
 }
 
+/// Archive format of multi-file sends
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+  #[serde(rename = "tar")]
+  Tar,
+  #[value(name = "tar.gz")]
+  #[serde(rename = "tar.gz")]
+  TarGz,
+  #[value(name = "tar.zst")]
+  #[serde(rename = "tar.zst")]
+  TarZst,
+  #[serde(rename = "zip")]
+  Zip,
+}
+
+impl ArchiveFormat {
+  /// Parse a format name as accepted by `--archive-format` and `?format=`
+  pub fn from_name(name: &str) -> Option<Self> {
+    <Self as ValueEnum>::from_str(name, true).ok()
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self {
+      Self::Tar => "tar",
+      Self::TarGz => "tar.gz",
+      Self::TarZst => "tar.zst",
+      Self::Zip => "zip",
+    }
+  }
+
+  pub fn mime_type(self) -> &'static str {
+    match self {
+      Self::Tar => "application/x-tar",
+      Self::TarGz => "application/gzip",
+      Self::TarZst => "application/zstd",
+      Self::Zip => "application/zip",
+    }
+  }
+
+  /// The command extracting this format from stdin, `None` if it cannot be streamed
+  pub fn extract_cmd(self) -> Option<&'static str> {
+    match self {
+      Self::Tar => Some("tar xvf -"),
+      Self::TarGz => Some("tar xzvf -"),
+      Self::TarZst => Some("zstd -d | tar xvf -"),
+      Self::Zip => None,
+    }
+  }
+}
+
+/// A transfer rate in bytes per second, written as plain bytes or with a `K`, `M` or `G` suffix (powers of 1024) like
+/// `500K` or `1.5M`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(
+      value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len()),
+    );
+    let number = number.parse::<f64>().map_err(|_| format!("invalid rate {:?}", value))?;
+    let unit = match unit.to_ascii_uppercase().trim_end_matches('B') {
+      "" => 1,
+      "K" => 1 << 10,
+      "M" => 1 << 20,
+      "G" => 1 << 30,
+      _ => return Err(format!("invalid rate unit {:?}, expected K, M or G", unit)),
+    };
+    match (number * unit as f64) as u64 {
+      0 => Err(format!("rate {:?} is less than 1 byte per second", value)),
+      rate => Ok(Self(rate)),
+    }
+  }
+}
+
+impl TryFrom<String> for Rate {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<Rate> for String {
+  fn from(rate: Rate) -> Self {
+    rate.0.to_string()
+  }
+}
+
+/// A proxy access token written as `NAME:SECRET`, keys registered with it are published under `NAME.<key>`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProxyToken {
+  pub name: String,
+  pub secret: String,
+}
+
+impl FromStr for ProxyToken {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let (name, secret) = value
+      .trim()
+      .split_once(':')
+      .ok_or_else(|| "invalid proxy token, expected NAME:SECRET".to_string())?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+      return Err(format!(
+        "invalid proxy token name {:?}, expected letters, digits, `-` or `_`",
+        name
+      ));
+    }
+    if secret.is_empty() {
+      return Err(format!("the proxy token {:?} has an empty secret", name));
+    }
+    Ok(Self {
+      name: name.to_string(),
+      secret: secret.to_string(),
+    })
+  }
+}
+
+impl TryFrom<String> for ProxyToken {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<ProxyToken> for String {
+  fn from(token: ProxyToken) -> Self {
+    format!("{}:{}", token.name, token.secret)
+  }
+}
+
+/// A proxy to register with, a host and the port of its control listener like `10.0.0.1`, `gw.lan:7000` or
+/// `[fd00::1]:7070`, the default control ports are tried when the port is left out
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProxyAddr {
+  pub host: String,
+  pub port: Option<u16>,
+}
+
+impl ProxyAddr {
+  /// Resolve the host, with `default_ports` if no port is given
+  pub fn socket_addrs(&self, default_ports: &[u16]) -> Vec<SocketAddr> {
+    let ports = self.port.map_or(default_ports.to_vec(), |port| vec![port]);
+    ports
+      .into_iter()
+      .flat_map(|port| match (self.host.as_str(), port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+          eprintln!("Resolve proxy {} failed: {}", self.host, e);
+          vec![]
+        }
+      })
+      .collect()
+  }
+}
+
+impl FromStr for ProxyAddr {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let value = value.trim();
+    if let Ok(ip) = IpAddr::from_str(value.trim_start_matches('[').trim_end_matches(']')) {
+      return Ok(Self {
+        host: ip.to_string(),
+        port: None,
+      });
+    }
+    if let Ok(socket) = SocketAddr::from_str(value) {
+      return Ok(Self {
+        host: socket.ip().to_string(),
+        port: Some(socket.port()),
+      });
+    }
+    let (host, port) = match value.rsplit_once(':') {
+      Some((host, port)) => (
+        host,
+        Some(
+          port
+            .parse::<u16>()
+            .map_err(|_| format!("invalid proxy port {:?}", port))?,
+        ),
+      ),
+      None => (value, None),
+    };
+    if host.is_empty() || host.contains([':', '[', ']', '/']) {
+      return Err(format!("invalid proxy {:?}, expected HOST or HOST:PORT", value));
+    }
+    Ok(Self {
+      host: host.to_string(),
+      port,
+    })
+  }
+}
+
+impl TryFrom<String> for ProxyAddr {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<ProxyAddr> for String {
+  fn from(addr: ProxyAddr) -> Self {
+    match addr.port {
+      Some(port) if addr.host.contains(':') => format!("[{}]:{}", addr.host, port),
+      Some(port) => format!("{}:{}", addr.host, port),
+      None => addr.host,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
   pub host: Option<IpAddr>,
+  pub interface: Option<String>,
+  pub bind: Option<IpAddr>,
   pub port: Option<u16>,
   pub key: Option<String>,
   pub reserve: bool,
-  pub proxy: Option<Vec<IpAddr>>,
+  pub proxy: Option<Vec<ProxyAddr>>,
   pub no_proxy: bool,
+  pub proxy_token: Option<Vec<ProxyToken>>,
+  pub control_addr: Option<Vec<SocketAddr>>,
   pub mode: Option<Mode>,
+  pub output_dir: Option<PathBuf>,
+  pub scratch_dir: Option<PathBuf>,
+  pub archive_format: Option<ArchiveFormat>,
+  pub limit_rate: Option<Rate>,
+  pub limit_rate_per_conn: Option<Rate>,
+  pub tls: bool,
+  pub tls_cert: Option<PathBuf>,
+  pub tls_key: Option<PathBuf>,
+  pub secure: bool,
   pub auto_rename: bool,
   pub prompt_save_config: bool,
 }
@@ -62,36 +299,74 @@ impl Config {
     }
   }
 
-  fn from_env() -> Self {
-    Self {
+  fn from_env() -> Result<Self, String> {
+    Ok(Self {
       host: match env::var("NCP_HOST") {
         Ok(x) => Some(IpAddr::from_str(&x).unwrap()),
         Err(_) => None,
       },
-      port: match env::var("NCP_PORT") {
-        Ok(x) => Some(u16::from_str(&x).unwrap()),
+      interface: env::var("NCP_INTERFACE").ok(),
+      bind: match env::var("NCP_BIND") {
+        Ok(x) => Some(IpAddr::from_str(&x).unwrap()),
         Err(_) => None,
       },
-      key: match env::var("NCP_KEY") {
-        Ok(x) => Some(x),
+      port: match env::var("NCP_PORT") {
+        Ok(x) => Some(u16::from_str(&x).unwrap()),
         Err(_) => None,
       },
+      key: env::var("NCP_KEY").ok(),
       reserve: match env::var("NCP_RESERVE") {
         Ok(x) => FromStr::from_str(&x).unwrap(),
         Err(_) => false,
       },
       proxy: match env::var("NCP_PROXY") {
-        Ok(x) => Some(x.split(':').map(|x| IpAddr::from_str(x).unwrap()).collect::<Vec<_>>()),
+        // Older versions separated IPv4 proxies by `:`, like `10.0.0.1:10.0.0.2`
+        Ok(x) if x.contains(':') && x.split(':').all(|ip| Ipv4Addr::from_str(ip).is_ok()) => {
+          Some(Self::parse_list("NCP_PROXY", &x.replace(':', ","))?)
+        }
+        Ok(x) => Some(Self::parse_list("NCP_PROXY", &x)?),
         Err(_) => None,
       },
       no_proxy: match env::var("NCP_NO_PROXY") {
         Ok(x) => FromStr::from_str(&x).unwrap(),
         Err(_) => false,
       },
+      proxy_token: match env::var("NCP_PROXY_TOKEN") {
+        Ok(x) => Some(Self::parse_list("NCP_PROXY_TOKEN", &x)?),
+        Err(_) => None,
+      },
+      control_addr: match env::var("NCP_CONTROL_ADDR") {
+        Ok(x) => Some(Self::parse_list("NCP_CONTROL_ADDR", &x)?),
+        Err(_) => None,
+      },
       mode: match env::var("NCP_MODE") {
         Ok(x) => Some(Mode::from_str(&x, true).unwrap()),
         Err(_) => None,
       },
+      output_dir: env::var("NCP_OUTPUT_DIR").ok().map(PathBuf::from),
+      scratch_dir: env::var("NCP_SCRATCH_DIR").ok().map(PathBuf::from),
+      archive_format: match env::var("NCP_ARCHIVE_FORMAT") {
+        Ok(x) => Some(ArchiveFormat::from_name(&x).unwrap()),
+        Err(_) => None,
+      },
+      limit_rate: match env::var("NCP_LIMIT_RATE") {
+        Ok(x) => Some(Rate::from_str(&x).unwrap()),
+        Err(_) => None,
+      },
+      limit_rate_per_conn: match env::var("NCP_LIMIT_RATE_PER_CONN") {
+        Ok(x) => Some(Rate::from_str(&x).unwrap()),
+        Err(_) => None,
+      },
+      tls: match env::var("NCP_TLS") {
+        Ok(x) => FromStr::from_str(&x).unwrap(),
+        Err(_) => false,
+      },
+      tls_cert: env::var("NCP_TLS_CERT").ok().map(PathBuf::from),
+      tls_key: env::var("NCP_TLS_KEY").ok().map(PathBuf::from),
+      secure: match env::var("NCP_SECURE") {
+        Ok(x) => FromStr::from_str(&x).unwrap(),
+        Err(_) => false,
+      },
       auto_rename: match env::var("NCP_AUTO_RENAME") {
         Ok(x) => FromStr::from_str(&x).unwrap(),
         Err(_) => false,
@@ -100,26 +375,50 @@ impl Config {
         Ok(x) => FromStr::from_str(&x).unwrap(),
         Err(_) => false,
       },
-    }
+    })
+  }
+
+  /// Parse the `,` separated items of the environment variable `name`
+  fn parse_list<T: FromStr<Err: Display>>(name: &str, value: &str) -> Result<Vec<T>, String> {
+    value
+      .split(',')
+      .map(|item| T::from_str(item.trim()).map_err(|e| format!("Invalid {} item {:?}: {}", name, item, e)))
+      .collect()
   }
 
   fn from_cli(cli: &Cli) -> Self {
     Self {
       host: cli.host,
+      interface: cli.interface.clone(),
+      bind: cli.bind,
       port: cli.port,
       key: cli.key.clone(),
       reserve: cli.reserve,
       proxy: cli.proxy.clone(),
       no_proxy: cli.no_proxy,
+      proxy_token: cli.proxy_token.clone(),
+      control_addr: cli.control_addr.clone(),
       mode: cli.mode.clone(),
+      output_dir: cli.output_dir.clone(),
+      scratch_dir: cli.scratch_dir.clone(),
+      archive_format: cli.archive_format,
+      limit_rate: cli.limit_rate,
+      limit_rate_per_conn: cli.limit_rate_per_conn,
+      tls: cli.tls,
+      tls_cert: cli.tls_cert.clone(),
+      tls_key: cli.tls_key.clone(),
+      secure: cli.secure,
       auto_rename: cli.auto_rename,
       prompt_save_config: cli.prompt_save_config,
     }
   }
 
   fn merge(&mut self, config: &Self) -> &mut Self {
-    if self.host.is_none() {
+    // The address selectors override each other, so they are taken together from the first source setting any
+    if self.host.is_none() && self.interface.is_none() && self.bind.is_none() {
       self.host = config.host;
+      self.interface = config.interface.clone();
+      self.bind = config.bind;
     }
     if self.port.is_none() {
       self.port = config.port;
@@ -136,9 +435,42 @@ impl Config {
     if !self.no_proxy {
       self.no_proxy = config.no_proxy;
     }
+    if self.proxy_token.is_none() {
+      self.proxy_token = config.proxy_token.clone();
+    }
+    if self.control_addr.is_none() {
+      self.control_addr = config.control_addr.clone();
+    }
     if self.mode.is_none() {
       self.mode = config.mode.clone();
     }
+    if self.output_dir.is_none() {
+      self.output_dir = config.output_dir.clone();
+    }
+    if self.scratch_dir.is_none() {
+      self.scratch_dir = config.scratch_dir.clone();
+    }
+    if self.archive_format.is_none() {
+      self.archive_format = config.archive_format;
+    }
+    if self.limit_rate.is_none() {
+      self.limit_rate = config.limit_rate;
+    }
+    if self.limit_rate_per_conn.is_none() {
+      self.limit_rate_per_conn = config.limit_rate_per_conn;
+    }
+    if !self.tls {
+      self.tls = config.tls;
+    }
+    if self.tls_cert.is_none() {
+      self.tls_cert = config.tls_cert.clone();
+    }
+    if self.tls_key.is_none() {
+      self.tls_key = config.tls_key.clone();
+    }
+    if !self.secure {
+      self.secure = config.secure;
+    }
     if !self.auto_rename {
       self.auto_rename = config.auto_rename;
     }
@@ -148,10 +480,10 @@ impl Config {
     self
   }
 
-  pub fn new(cli: &Cli) -> Self {
+  pub fn new(cli: &Cli) -> Result<Self, String> {
     let mut config = Self::from_cli(cli);
-    config.merge(&Self::from_env()).merge(&Self::from_file());
-    config
+    config.merge(&Self::from_env()?).merge(&Self::from_file());
+    Ok(config)
   }
 
   pub fn save(&self) {
@@ -165,39 +497,57 @@ impl Config {
       return;
     }
     let mut input = String::new();
-    println!("Save config? [y/N]");
+    eprintln!("Save config? [y/N]");
     match std::io::stdin().read_line(&mut input) {
       Ok(_) => {
         if input.trim().to_uppercase() == "Y" {
-          if let Some(parent) = config_file_path.parent() {
-            if !parent.is_dir() {
-              if let Err(e) = create_dir_all(parent) {
-                println!("Create directoty {:?} for config file failed: {}", parent, e);
-                return;
-              }
-            }
+          if let Some(parent) = config_file_path.parent()
+            && !parent.is_dir()
+            && let Err(e) = create_dir_all(parent)
+          {
+            eprintln!("Create directoty {:?} for config file failed: {}", parent, e);
+            return;
           }
+          let optional = |name: &str, value: Option<String>| match value {
+            Some(value) => format!("{} = \"{}\"\n", name, value),
+            None => format!("# {} = \n", name),
+          };
           let config_str = format!(
             "\
-              host = \"{}\"\n\
+              {}\
+              {}\
+              {}\
               # port = \n\
               # key = \n\
               reserve = false\n\
               proxy = []\n\
               no_proxy = false\n\
+              # proxy_token = [\"name:secret\"]\n\
+              # control_addr = [\"0.0.0.0:7070\", \"[::]:7070\"]\n\
               # mode = \"normal\"\n\
+              # output_dir = \n\
+              # scratch_dir = \n\
+              # archive_format = \"tar\"\n\
+              # limit_rate = \"20M\"\n\
+              # limit_rate_per_conn = \"5M\"\n\
+              tls = false\n\
+              # tls_cert = \n\
+              # tls_key = \n\
+              secure = false\n\
               auto_rename = false\n\
               ",
-            self.host.unwrap(),
+            optional("host", self.host.map(|host| host.to_string())),
+            optional("interface", self.interface.clone()),
+            optional("bind", self.bind.map(|bind| bind.to_string())),
           );
           File::create(&config_file_path)
             .expect("Create config file failed")
             .write_all(config_str.as_bytes())
             .expect("Write to config file failed");
-          println!("Config has been written to {}", config_file_path.to_str().unwrap());
+          eprintln!("Config has been written to {}", config_file_path.to_str().unwrap());
         }
       }
-      Err(_) => println!("Save config aborted"),
+      Err(_) => eprintln!("Save config aborted"),
     }
   }
 }
@@ -0,0 +1,263 @@
+use std::io::{self, Read, Write};
+
+use base64::{Engine as _, engine::general_purpose};
+use md5::Md5;
+use sha2::{Digest as _, Sha256};
+
+use crate::http;
+
+/// Names of the header fields written by `Digest::to_headers`, for announcing them as trailers
+#[cfg(not(feature = "blake3"))]
+pub const HEADER_NAMES: &str = "Repr-Digest";
+#[cfg(feature = "blake3")]
+pub const HEADER_NAMES: &str = "Repr-Digest, X-Checksum-Blake3";
+
+/// Checksum supplied by the peer for verifying the transferred content
+pub enum Checksum {
+  Sha256(Vec<u8>),
+  Md5(Vec<u8>),
+  #[cfg(feature = "blake3")]
+  Blake3(Vec<u8>),
+}
+
+impl Checksum {
+  /// Collect checksums from `Repr-Digest`, `Content-Digest`, `Digest`, `Content-MD5` and `X-Checksum-*` headers,
+  /// unknown algorithms are ignored
+  pub fn from_headers(headers: &[String]) -> Result<Vec<Self>, String> {
+    let mut checksums = vec![];
+    for name in ["Repr-Digest", "Content-Digest", "Digest"] {
+      let Some(value) = http::header(headers, name) else {
+        continue;
+      };
+      for item in value.split(',') {
+        let Some((algorithm, value)) = item.split_once('=') else {
+          return Err(format!("bad {} header: {}", name, value));
+        };
+        let value = value.trim().trim_matches(':');
+        match algorithm.trim().to_lowercase().as_str() {
+          "sha-256" => checksums.push(Self::Sha256(decode_base64(name, value)?)),
+          "md5" => checksums.push(Self::Md5(decode_base64(name, value)?)),
+          _ => {}
+        }
+      }
+    }
+    if let Some(value) = http::header(headers, "Content-MD5") {
+      checksums.push(Self::Md5(decode_base64("Content-MD5", value)?));
+    }
+    if let Some(value) = http::header(headers, "X-Checksum-Sha256") {
+      checksums.push(Self::Sha256(decode_hex("X-Checksum-Sha256", value)?));
+    }
+    #[cfg(feature = "blake3")]
+    if let Some(value) = http::header(headers, "X-Checksum-Blake3") {
+      checksums.push(Self::Blake3(decode_hex("X-Checksum-Blake3", value)?));
+    }
+    Ok(checksums)
+  }
+
+  fn needs_md5(checksums: &[Self]) -> bool {
+    checksums.iter().any(|checksum| matches!(checksum, Self::Md5(_)))
+  }
+}
+
+fn decode_base64(name: &str, value: &str) -> Result<Vec<u8>, String> {
+  general_purpose::STANDARD
+    .decode(value.trim())
+    .map_err(|e| format!("decode {} failed: {}", name, e))
+}
+
+fn decode_hex(name: &str, value: &str) -> Result<Vec<u8>, String> {
+  let value = value.trim();
+  if !value.len().is_multiple_of(2) {
+    return Err(format!("decode {} failed: odd length", name));
+  }
+  (0..value.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(value.get(i..i + 2).unwrap_or_default(), 16))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("decode {} failed: {}", name, e))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Incremental hasher, feed it with `update` or by writing into it
+pub struct Hasher {
+  sha256: Sha256,
+  md5: Option<Md5>,
+  #[cfg(feature = "blake3")]
+  blake3: blake3::Hasher,
+}
+
+impl Hasher {
+  pub fn new() -> Self {
+    Self {
+      sha256: Sha256::new(),
+      md5: None,
+      #[cfg(feature = "blake3")]
+      blake3: blake3::Hasher::new(),
+    }
+  }
+
+  /// Create a hasher that is able to verify all of `checksums`
+  pub fn for_checksums(checksums: &[Checksum]) -> Self {
+    let mut hasher = Self::new();
+    if Checksum::needs_md5(checksums) {
+      hasher.md5 = Some(Md5::new());
+    }
+    hasher
+  }
+
+  /// Also compute MD5, for checksums which are only known after hashing (e.g. trailers)
+  pub fn with_md5(mut self) -> Self {
+    self.md5.get_or_insert_with(Md5::new);
+    self
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    self.sha256.update(data);
+    if let Some(md5) = &mut self.md5 {
+      md5.update(data);
+    }
+    #[cfg(feature = "blake3")]
+    self.blake3.update(data);
+  }
+
+  pub fn finalize(self) -> Digest {
+    Digest {
+      sha256: self.sha256.finalize().to_vec(),
+      md5: self.md5.map(|md5| md5.finalize().to_vec()),
+      #[cfg(feature = "blake3")]
+      blake3: self.blake3.finalize().as_bytes().to_vec(),
+    }
+  }
+}
+
+impl Default for Hasher {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Write for Hasher {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.update(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(Clone)]
+pub struct Digest {
+  pub sha256: Vec<u8>,
+  pub md5: Option<Vec<u8>>,
+  #[cfg(feature = "blake3")]
+  pub blake3: Vec<u8>,
+}
+
+impl Digest {
+  /// Response header (or trailer) lines carrying this digest
+  pub fn to_headers(&self) -> String {
+    #[allow(unused_mut)]
+    let mut headers = format!(
+      "Repr-Digest: sha-256=:{}:\r\n",
+      general_purpose::STANDARD.encode(&self.sha256)
+    );
+    #[cfg(feature = "blake3")]
+    headers.push_str(&format!("X-Checksum-Blake3: {}\r\n", to_hex(&self.blake3)));
+    headers
+  }
+
+  pub fn verify(&self, checksums: &[Checksum]) -> Result<(), String> {
+    for checksum in checksums {
+      let (name, expected, actual) = match checksum {
+        Checksum::Sha256(expected) => ("sha-256", expected, &self.sha256),
+        Checksum::Md5(expected) => match &self.md5 {
+          Some(actual) => ("md5", expected, actual),
+          None => return Err("md5 was not computed".to_string()),
+        },
+        #[cfg(feature = "blake3")]
+        Checksum::Blake3(expected) => ("blake3", expected, &self.blake3),
+      };
+      if expected != actual {
+        return Err(format!(
+          "{} mismatch, expected {} but got {}",
+          name,
+          to_hex(expected),
+          to_hex(actual)
+        ));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl std::fmt::Display for Digest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "sha-256: {}", to_hex(&self.sha256))?;
+    #[cfg(feature = "blake3")]
+    write!(f, ", blake3: {}", to_hex(&self.blake3))?;
+    Ok(())
+  }
+}
+
+/// Writer that hashes everything passing through to the inner writer
+pub struct HashWriter<W: Write> {
+  inner: W,
+  hasher: Hasher,
+}
+
+impl<W: Write> HashWriter<W> {
+  pub fn new(inner: W) -> Self {
+    Self {
+      inner,
+      hasher: Hasher::new(),
+    }
+  }
+
+  pub fn finish(self) -> (W, Digest) {
+    (self.inner, self.hasher.finalize())
+  }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.hasher.update(&buf[..n]);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Reader that hashes everything read from the inner reader
+pub struct HashReader<R: Read> {
+  inner: R,
+  hasher: Hasher,
+}
+
+impl<R: Read> HashReader<R> {
+  pub fn new(inner: R) -> Self {
+    Self {
+      inner,
+      hasher: Hasher::new(),
+    }
+  }
+
+  pub fn finish(self) -> (R, Digest) {
+    (self.inner, self.hasher.finalize())
+  }
+}
+
+impl<R: Read> Read for HashReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.hasher.update(&buf[..n]);
+    Ok(n)
+  }
+}
@@ -0,0 +1,63 @@
+use std::{
+  cell::Cell,
+  sync::{Condvar, Mutex},
+  time::{Duration, Instant},
+};
+
+/// How long a stopping server waits for the connections still being served
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connections being served and whether the server is stopping, no more connections are served once it is
+static STATE: Mutex<(u64, bool)> = Mutex::new((0, false));
+static IDLE: Condvar = Condvar::new();
+
+thread_local! {
+  // Every connection is served by a thread of its own
+  static SERVING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Counts the connection of the current thread as being served until dropped
+pub struct Guard(());
+
+impl Drop for Guard {
+  fn drop(&mut self) {
+    SERVING.set(false);
+    STATE.lock().unwrap().0 -= 1;
+    IDLE.notify_all();
+  }
+}
+
+/// Count the connection of the current thread, `None` once the server is stopping and the connection should be dropped
+pub fn enter() -> Option<Guard> {
+  let mut state = STATE.lock().unwrap();
+  if state.1 {
+    return None;
+  }
+  state.0 += 1;
+  SERVING.set(true);
+  Some(Guard(()))
+}
+
+/// Stop serving new connections and wait up to [`TIMEOUT`] for the others being served, the connection of the calling
+/// thread is not waited for
+pub fn stop() {
+  let own = SERVING.get() as u64;
+  let mut state = STATE.lock().unwrap();
+  state.1 = true;
+  if state.0 > own {
+    eprintln!(
+      "Waiting up to {:?} for {} more connections being served",
+      TIMEOUT,
+      state.0 - own
+    );
+  }
+  let deadline = Instant::now() + TIMEOUT;
+  while state.0 > own {
+    let now = Instant::now();
+    if now >= deadline {
+      eprintln!("Gave up waiting for {} connections", state.0 - own);
+      return;
+    }
+    state = IDLE.wait_timeout(state, deadline - now).unwrap().0;
+  }
+}
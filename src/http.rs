@@ -0,0 +1,655 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Writer for HTTP/1.1 `Transfer-Encoding: chunked` bodies
+pub struct ChunkedWriter<W: Write> {
+  inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+  pub fn new(inner: W) -> Self {
+    Self { inner }
+  }
+
+  /// Write the terminating chunk and return the underlying writer
+  pub fn finish(self) -> io::Result<W> {
+    self.finish_with_trailers("")
+  }
+
+  /// Write the terminating chunk followed by `trailers` (header lines ending with CRLF)
+  pub fn finish_with_trailers(mut self, trailers: &str) -> io::Result<W> {
+    self.inner.write_all(format!("0\r\n{trailers}\r\n").as_bytes())?;
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    self.inner.write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+    self.inner.write_all(buf)?;
+    self.inner.write_all(b"\r\n")?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Reader for HTTP/1.1 `Transfer-Encoding: chunked` bodies, the trailer lines are collected after the last chunk
+pub struct ChunkedReader<R: BufRead> {
+  inner: R,
+  left: u64,
+  done: bool,
+  pub trailers: Vec<String>,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+  pub fn new(inner: R) -> Self {
+    Self {
+      inner,
+      left: 0,
+      done: false,
+      trailers: vec![],
+    }
+  }
+
+  pub fn into_inner(self) -> R {
+    self.inner
+  }
+
+  fn next_chunk(&mut self) -> io::Result<()> {
+    self.left = parse_chunk_size(&read_chunked_line(&mut self.inner)?)?;
+    if self.left == 0 {
+      loop {
+        let line = read_chunked_line(&mut self.inner)?;
+        if line == "\r\n" {
+          break;
+        }
+        self.trailers.push(line);
+        if self.trailers.len() > 100 {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "too many trailers"));
+        }
+      }
+      self.done = true;
+    }
+    Ok(())
+  }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.left == 0 && !self.done {
+      self.next_chunk()?;
+    }
+    if self.done || buf.is_empty() {
+      return Ok(0);
+    }
+    let limit = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+    let n = self.inner.read(&mut buf[..limit])?;
+    if n == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "chunked body ended unexpectedly",
+      ));
+    }
+    self.left -= n as u64;
+    if self.left == 0 && read_chunked_line(&mut self.inner)? != "\r\n" {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "missing CRLF after chunk"));
+    }
+    Ok(n)
+  }
+}
+
+fn read_chunked_line<R: BufRead + ?Sized>(reader: &mut R) -> io::Result<String> {
+  let mut line = String::new();
+  if reader.read_line(&mut line)? == 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "chunked body ended unexpectedly",
+    ));
+  }
+  Ok(line)
+}
+
+fn parse_chunk_size(line: &str) -> io::Result<u64> {
+  let size = line.split(';').next().unwrap_or_default().trim();
+  u64::from_str_radix(size, 16)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad chunk size {:?}: {}", size, e)))
+}
+
+/// Copy a chunked body verbatim, framing and trailers included, up to its terminating empty line
+pub fn copy_chunked<R: BufRead + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+  let mut copied = 0;
+  loop {
+    let line = read_chunked_line(reader)?;
+    writer.write_all(line.as_bytes())?;
+    let size = parse_chunk_size(&line)?;
+    if size == 0 {
+      loop {
+        let line = read_chunked_line(reader)?;
+        writer.write_all(line.as_bytes())?;
+        if line == "\r\n" {
+          return Ok(copied);
+        }
+      }
+    }
+    // The chunk data is followed by CRLF
+    if io::copy(&mut reader.take(size + 2), writer)? < size + 2 {
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "chunked body ended unexpectedly",
+      ));
+    }
+    copied += size;
+  }
+}
+
+/// Reader of a message body framed by either `Content-Length` or `Transfer-Encoding: chunked`
+pub enum BodyReader<R: BufRead> {
+  Sized(io::Take<R>),
+  Chunked(ChunkedReader<R>),
+}
+
+impl<R: BufRead> BodyReader<R> {
+  /// Trailer lines of a chunked body, available once it has been read to the end
+  pub fn trailers(&self) -> &[String] {
+    match self {
+      Self::Sized(_) => &[],
+      Self::Chunked(reader) => &reader.trailers,
+    }
+  }
+}
+
+impl<R: BufRead> Read for BodyReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Self::Sized(reader) => reader.read(buf),
+      Self::Chunked(reader) => reader.read(buf),
+    }
+  }
+}
+
+/// Whether the message of `headers` has a chunked body
+pub fn is_chunked(headers: &[String]) -> bool {
+  header(headers, "Transfer-Encoding").is_some_and(|value| value.to_lowercase().contains("chunked"))
+}
+
+/// Find the value of header `name` (case-insensitive) in raw request/response header lines
+pub fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+  headers.iter().find_map(|line| match line.split_once(':') {
+    Some((key, value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+    _ => None,
+  })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+  /// Serve the whole representation
+  Full,
+  /// Serve the inclusive range `start..=end`
+  Partial(u64, u64),
+  Unsatisfiable,
+}
+
+impl ByteRange {
+  /// Parse a `Range` header value against a representation of `size` bytes, only a single range is supported and
+  /// anything else falls back to the full representation
+  pub fn parse(value: &str, size: u64) -> Self {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+      return Self::Full;
+    };
+    if spec.contains(',') {
+      return Self::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+      return Self::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+      return match end.parse::<u64>() {
+        Ok(0) => Self::Unsatisfiable,
+        Ok(_) if size == 0 => Self::Unsatisfiable,
+        Ok(suffix) => Self::Partial(size.saturating_sub(suffix), size - 1),
+        Err(_) => Self::Full,
+      };
+    }
+    let Ok(start) = start.parse::<u64>() else {
+      return Self::Full;
+    };
+    let end = if end.is_empty() {
+      size.saturating_sub(1)
+    } else {
+      match end.parse::<u64>() {
+        Ok(end) if end < start => return Self::Full,
+        Ok(end) => end.min(size.saturating_sub(1)),
+        Err(_) => return Self::Full,
+      }
+    };
+    if start >= size {
+      Self::Unsatisfiable
+    } else {
+      Self::Partial(start, end)
+    }
+  }
+}
+
+/// Parse a `Content-Range: bytes <start>-<end>/<total>` header value
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+  let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+  let (start, end) = range.split_once('-')?;
+  let (start, end, total) = (
+    start.trim().parse::<u64>().ok()?,
+    end.trim().parse::<u64>().ok()?,
+    total.trim().parse::<u64>().ok()?,
+  );
+  if start > end || end >= total {
+    None
+  } else {
+    Some((start, end, total))
+  }
+}
+
+/// The `filename` parameter of a `Content-Disposition` header value
+pub fn content_disposition_filename(value: &str) -> Option<&str> {
+  value.split(';').find_map(|param| {
+    let (name, value) = param.split_once('=')?;
+    if name.trim().eq_ignore_ascii_case("filename") {
+      Some(value.trim().trim_matches('"'))
+    } else {
+      None
+    }
+  })
+}
+
+/// Decode `%XX` escapes of a URL path, `None` if an escape is malformed or the result is not UTF-8
+pub fn percent_decode(value: &str) -> Option<String> {
+  let bytes = value.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+      decoded.push(u8::from_str_radix(hex, 16).ok()?);
+      i += 3;
+    } else {
+      decoded.push(bytes[i]);
+      i += 1;
+    }
+  }
+  String::from_utf8(decoded).ok()
+}
+
+/// Escape everything but unreserved characters and `/` for use in a URL path
+pub fn percent_encode(value: &str) -> String {
+  value
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// Escape text for HTML content and attribute values
+pub fn html_escape(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#39;")
+}
+
+/// Pick the content coding of a response from an `Accept-Encoding` header value, the highest quality wins and ties are
+/// broken by the order of `supported`
+pub fn accept_encoding(value: &str, supported: &[&'static str]) -> Option<&'static str> {
+  let mut best: Option<(&'static str, f32)> = None;
+  for coding in supported {
+    let quality = value.split(',').find_map(|item| {
+      let mut params = item.split(';');
+      if !params.next()?.trim().eq_ignore_ascii_case(coding) {
+        return None;
+      }
+      Some(
+        params
+          .find_map(|param| param.trim().strip_prefix("q="))
+          .and_then(|q| q.trim().parse::<f32>().ok())
+          .unwrap_or(1.0),
+      )
+    });
+    if let Some(quality) = quality
+      && quality > 0.0
+      && best.is_none_or(|(_, best)| quality > best)
+    {
+      best = Some((coding, quality));
+    }
+  }
+  best.map(|(coding, _)| coding)
+}
+
+/// The boundary of a `multipart/form-data` `Content-Type` header value
+pub fn multipart_boundary(value: &str) -> Option<&str> {
+  let mut params = value.split(';');
+  if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+    return None;
+  }
+  params
+    .find_map(|param| {
+      let (name, value) = param.split_once('=')?;
+      name
+        .trim()
+        .eq_ignore_ascii_case("boundary")
+        .then(|| value.trim().trim_matches('"'))
+    })
+    .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// Streaming reader of `multipart/form-data` bodies, `next_part` moves to the next part and returns its header lines,
+/// the content of the part is then read until the following boundary
+pub struct MultipartReader<R: Read> {
+  inner: R,
+  delimiter: Vec<u8>,
+  buf: Vec<u8>,
+  in_part: bool,
+  done: bool,
+}
+
+impl<R: Read> MultipartReader<R> {
+  pub fn new(inner: R, boundary: &str) -> Self {
+    Self {
+      inner,
+      delimiter: format!("\r\n--{boundary}").into_bytes(),
+      // The first boundary is not preceded by CRLF, the preamble is skipped like the rest of a part
+      buf: b"\r\n".to_vec(),
+      in_part: true,
+      done: false,
+    }
+  }
+
+  fn fill(&mut self) -> io::Result<usize> {
+    let len = self.buf.len();
+    self.buf.resize(len + 16 * 1024, 0);
+    let n = self.inner.read(&mut self.buf[len..]);
+    self.buf.truncate(len + n.as_ref().map_or(0, |n| *n));
+    n
+  }
+
+  fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body ended unexpectedly")
+  }
+
+  fn read_line(&mut self) -> io::Result<String> {
+    loop {
+      if let Some(pos) = self.buf.windows(2).position(|window| window == b"\r\n") {
+        let line = String::from_utf8_lossy(&self.buf[..pos]).into_owned();
+        self.buf.drain(..pos + 2);
+        return Ok(line);
+      }
+      if self.buf.len() > 8 * 1024 {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "multipart header line too long",
+        ));
+      }
+      if self.fill()? == 0 {
+        return Err(Self::unexpected_eof());
+      }
+    }
+  }
+
+  /// Skip the rest of the current part and return the header lines of the next one, `None` after the closing boundary
+  pub fn next_part(&mut self) -> io::Result<Option<Vec<String>>> {
+    if self.done {
+      return Ok(None);
+    }
+    io::copy(self, &mut io::sink())?;
+    while self.buf.len() < 2 {
+      if self.fill()? == 0 {
+        return Err(Self::unexpected_eof());
+      }
+    }
+    if self.buf.starts_with(b"--") {
+      self.done = true;
+      return Ok(None);
+    }
+    // Only transport padding may follow the boundary
+    if !self.read_line()?.trim().is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "bad multipart boundary line",
+      ));
+    }
+    let mut headers = vec![];
+    loop {
+      let line = self.read_line()?;
+      if line.is_empty() {
+        break;
+      }
+      headers.push(line);
+      if headers.len() > 100 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many multipart headers"));
+      }
+    }
+    self.in_part = true;
+    Ok(Some(headers))
+  }
+}
+
+impl<R: Read> Read for MultipartReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if !self.in_part || buf.is_empty() {
+      return Ok(0);
+    }
+    loop {
+      // Bytes which could begin the delimiter are held back until more data arrives
+      let available = match self
+        .buf
+        .windows(self.delimiter.len())
+        .position(|window| window == self.delimiter)
+      {
+        Some(0) => {
+          self.buf.drain(..self.delimiter.len());
+          self.in_part = false;
+          return Ok(0);
+        }
+        Some(pos) => pos,
+        None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+      };
+      if available > 0 {
+        let n = available.min(buf.len());
+        buf[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.drain(..n);
+        return Ok(n);
+      }
+      if self.fill()? == 0 {
+        return Err(Self::unexpected_eof());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Hands out at most `step` bytes per read, so that parsers see their input split at every position
+  struct Trickle<'a> {
+    data: &'a [u8],
+    step: usize,
+  }
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let n = buf.len().min(self.step).min(self.data.len());
+      buf[..n].copy_from_slice(&self.data[..n]);
+      self.data = &self.data[n..];
+      Ok(n)
+    }
+  }
+
+  fn read_chunked(body: &[u8]) -> io::Result<(Vec<u8>, Vec<String>)> {
+    let mut reader = ChunkedReader::new(io::BufReader::with_capacity(3, Trickle { data: body, step: 2 }));
+    let mut content = vec![];
+    reader.read_to_end(&mut content)?;
+    Ok((content, reader.trailers))
+  }
+
+  fn read_multipart(body: &[u8], step: usize) -> io::Result<Vec<(Vec<String>, Vec<u8>)>> {
+    let mut reader = MultipartReader::new(Trickle { data: body, step }, "XyZ");
+    let mut parts = vec![];
+    while let Some(headers) = reader.next_part()? {
+      let mut content = vec![];
+      reader.read_to_end(&mut content)?;
+      parts.push((headers, content));
+    }
+    Ok(parts)
+  }
+
+  const MULTIPART: &[u8] = b"preamble\r\n--XyZ\r\n\
+    Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\r\n\
+    hello\r\n--XY not the boundary\r\n--XyZ \r\n\
+    Content-Disposition: form-data; name=\"b\"\r\n\r\n\
+    \r\n--XyZ--\r\nepilogue";
+
+  #[test]
+  fn reads_chunks_and_trailers() {
+    let (content, trailers) = read_chunked(b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nDigest: x\r\n\r\n").unwrap();
+    assert_eq!(content, b"hello world");
+    assert_eq!(trailers, ["Digest: x\r\n"]);
+  }
+
+  #[test]
+  fn rejects_malformed_chunk_sizes() {
+    for body in [
+      &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+      b"\r\nhello\r\n0\r\n\r\n",
+      b"-5\r\nhello\r\n0\r\n\r\n",
+      b"10000000000000000\r\nhello\r\n0\r\n\r\n",
+    ] {
+      assert_eq!(read_chunked(body).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+  }
+
+  #[test]
+  fn rejects_bad_chunk_framing() {
+    assert_eq!(
+      read_chunked(b"5\r\nhelloX\r\n0\r\n\r\n").unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+      read_chunked(b"5\r\nhel").unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    assert_eq!(
+      read_chunked(b"5\r\nhello\r\n").unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    assert_eq!(
+      read_chunked(b"0\r\nDigest: x\r\n").unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    let trailers = "X: y\r\n".repeat(101);
+    assert_eq!(
+      read_chunked(format!("0\r\n{}\r\n", trailers).as_bytes())
+        .unwrap_err()
+        .kind(),
+      io::ErrorKind::InvalidData
+    );
+  }
+
+  #[test]
+  fn copies_chunked_bodies_verbatim() {
+    let body = b"5\r\nhello\r\n0\r\nDigest: x\r\n\r\nnext request";
+    let mut reader = &body[..];
+    let mut copied = vec![];
+    assert_eq!(copy_chunked(&mut reader, &mut copied).unwrap(), 5);
+    assert_eq!(copied, &body[..body.len() - b"next request".len()]);
+    assert_eq!(reader, b"next request");
+    assert!(copy_chunked(&mut &b"5\r\nhel"[..], &mut vec![]).is_err());
+  }
+
+  #[test]
+  fn reads_multipart_parts_split_across_reads() {
+    for step in [1, 2, 3, 7, 16 * 1024] {
+      let parts = read_multipart(MULTIPART, step).unwrap();
+      assert_eq!(parts.len(), 2);
+      assert_eq!(
+        parts[0].0,
+        ["Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\""]
+      );
+      assert_eq!(parts[0].1, b"hello\r\n--XY not the boundary");
+      assert_eq!(parts[1].1, b"");
+    }
+  }
+
+  #[test]
+  fn rejects_multipart_without_final_boundary() {
+    let body = &MULTIPART[..MULTIPART.len() - b"\r\n--XyZ--\r\nepilogue".len()];
+    assert_eq!(
+      read_multipart(body, 5).unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    assert_eq!(
+      read_multipart(b"--XyZ\r\nX: y\r\n", 5).unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    assert_eq!(
+      read_multipart(b"--XyZjunk\r\n\r\n\r\n--XyZ--", 5).unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+  }
+
+  #[test]
+  fn parses_content_ranges() {
+    assert_eq!(parse_content_range("bytes 0-99/1000"), Some((0, 99, 1000)));
+    assert_eq!(parse_content_range(" bytes 900-999/1000 "), Some((900, 999, 1000)));
+    assert_eq!(parse_content_range("bytes 100-0/1000"), None);
+    assert_eq!(parse_content_range("bytes 900-1000/1000"), None);
+    assert_eq!(parse_content_range("bytes 0-99/50"), None);
+    assert_eq!(parse_content_range("bytes */1000"), None);
+    assert_eq!(parse_content_range("bytes 0-99/*"), None);
+    assert_eq!(parse_content_range("0-99/1000"), None);
+    assert_eq!(parse_content_range("bytes -1-99/1000"), None);
+  }
+
+  #[test]
+  fn parses_suffix_ranges() {
+    assert_eq!(ByteRange::parse("bytes=-100", 1000), ByteRange::Partial(900, 999));
+    assert_eq!(ByteRange::parse("bytes=-2000", 1000), ByteRange::Partial(0, 999));
+    assert_eq!(ByteRange::parse("bytes=-0", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(ByteRange::parse("bytes=-5", 0), ByteRange::Unsatisfiable);
+  }
+
+  #[test]
+  fn parses_open_ranges() {
+    assert_eq!(ByteRange::parse("bytes=100-", 1000), ByteRange::Partial(100, 999));
+    assert_eq!(ByteRange::parse("bytes=0-", 1000), ByteRange::Partial(0, 999));
+    assert_eq!(ByteRange::parse("bytes=999-", 1000), ByteRange::Partial(999, 999));
+  }
+
+  #[test]
+  fn ignores_reversed_ranges() {
+    assert_eq!(ByteRange::parse("bytes=500-100", 1000), ByteRange::Full);
+  }
+
+  #[test]
+  fn clamps_or_refuses_ranges_past_the_end() {
+    assert_eq!(ByteRange::parse("bytes=900-5000", 1000), ByteRange::Partial(900, 999));
+    assert_eq!(ByteRange::parse("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(ByteRange::parse("bytes=1000-1100", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(ByteRange::parse("bytes=0-", 0), ByteRange::Unsatisfiable);
+  }
+
+  #[test]
+  fn serves_multiple_or_malformed_ranges_in_full() {
+    assert_eq!(ByteRange::parse("bytes=0-1,5-9", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("bytes=0-1, -5", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("items=0-1", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("bytes=a-b", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("bytes=5", 1000), ByteRange::Full);
+  }
+}
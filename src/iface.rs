@@ -0,0 +1,118 @@
+use std::{
+  io,
+  net::{IpAddr, SocketAddr, TcpListener},
+};
+
+use default_net::Interface;
+use socket2::{Domain, Socket, Type};
+
+/// An address of a network interface that can be served on
+pub struct Candidate {
+  pub name: String,
+  pub addr: IpAddr,
+}
+
+impl std::fmt::Display for Candidate {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.addr {
+      IpAddr::V4(addr) => write!(f, "<{}> {}", self.name, addr),
+      IpAddr::V6(addr) => write!(f, "<{}> [{}]", self.name, addr),
+    }
+  }
+}
+
+fn interface_addrs(interface: &Interface) -> impl Iterator<Item = Candidate> + '_ {
+  let name = interface.friendly_name.as_ref().unwrap_or(&interface.name);
+  let ipv4 = interface
+    .ipv4
+    .iter()
+    .filter(|net| !net.addr.is_loopback() && !net.addr.is_broadcast())
+    .map(|net| IpAddr::V4(net.addr));
+  let ipv6 = interface
+    .ipv6
+    .iter()
+    .filter(|net| !net.addr.is_loopback())
+    .map(|net| IpAddr::V6(net.addr));
+  ipv4.chain(ipv6).map(|addr| Candidate {
+    name: name.clone(),
+    addr,
+  })
+}
+
+/// All usable interface addresses, IPv4 ones first
+pub fn candidates() -> Vec<Candidate> {
+  let (mut ipv4, ipv6): (Vec<_>, Vec<_>) = default_net::get_interfaces()
+    .iter()
+    .flat_map(interface_addrs)
+    .partition(|candidate| candidate.addr.is_ipv4());
+  ipv4.extend(ipv6);
+  ipv4
+}
+
+/// The first address of the interface called `name`
+pub fn by_name(name: &str) -> Option<Candidate> {
+  default_net::get_interfaces()
+    .iter()
+    .filter(|interface| interface.name == name || interface.friendly_name.as_deref() == Some(name))
+    .flat_map(interface_addrs)
+    .find(|candidate| !is_link_local(candidate.addr))
+}
+
+fn is_link_local(addr: IpAddr) -> bool {
+  match addr {
+    IpAddr::V4(addr) => addr.is_link_local(),
+    IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+  }
+}
+
+/// Bridges and virtual links of containers and VMs, they are hardly reachable from other machines
+fn is_virtual(name: &str) -> bool {
+  ["docker", "veth", "br-", "virbr", "vmnet", "cni", "flannel"]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+/// Pick an address without asking: the interface holding the default route is preferred, then any other interface
+/// which is neither virtual nor link-local
+pub fn auto() -> Option<Candidate> {
+  if let Ok(interface) = default_net::get_default_interface()
+    && let Some(candidate) = interface_addrs(&interface).find(|candidate| !is_link_local(candidate.addr))
+  {
+    return Some(candidate);
+  }
+  candidates()
+    .into_iter()
+    .find(|candidate| !is_virtual(&candidate.name) && !is_link_local(candidate.addr))
+}
+
+/// The addresses peers can reach a server listening on `socket` by, the likely best one first. An unspecified address
+/// stands for every interface address of the same family, or of both families for `::` which is bound dual-stack
+pub fn public_addrs(socket: SocketAddr) -> Vec<SocketAddr> {
+  if !socket.ip().is_unspecified() {
+    return vec![socket];
+  }
+  let best = auto().map(|candidate| candidate.addr);
+  let mut addrs: Vec<_> = candidates()
+    .into_iter()
+    .filter(|candidate| (socket.is_ipv6() || candidate.addr.is_ipv4()) && !is_link_local(candidate.addr))
+    .map(|candidate| SocketAddr::new(candidate.addr, socket.port()))
+    .collect();
+  if let Some(i) = addrs.iter().position(|addr| Some(addr.ip()) == best) {
+    let addr = addrs.remove(i);
+    addrs.insert(0, addr);
+  }
+  if addrs.is_empty() { vec![socket] } else { addrs }
+}
+
+/// Bind a TCP listener on `socket`, `::` accepts IPv4 peers as well
+pub fn listen(socket: SocketAddr) -> io::Result<TcpListener> {
+  let listener = Socket::new(Domain::for_address(socket), Type::STREAM, None)?;
+  if socket.is_ipv6() && socket.ip().is_unspecified() {
+    listener.set_only_v6(false)?;
+  }
+  #[cfg(not(windows))]
+  listener.set_reuse_address(true)?;
+  listener.bind(&socket.into())?;
+  listener.listen(128)?;
+  Ok(listener.into())
+}
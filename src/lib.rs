@@ -1,5 +1,15 @@
 pub mod cli;
+pub mod client;
 pub mod config;
+pub mod digest;
+pub mod drain;
+pub mod http;
+pub mod iface;
+pub mod mux;
 pub mod proxy;
+pub mod rate;
 pub mod recv;
+pub mod secure;
 pub mod send;
+pub mod stream;
+pub mod tls;
@@ -1,6 +1,8 @@
 use std::{
-  io::{self, Write},
+  env,
+  io::{self, IsTerminal, Write},
   net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+  sync::Arc,
 };
 
 use clap::Parser;
@@ -8,84 +10,113 @@ use portpicker::pick_unused_port;
 use rand::distr::{Alphanumeric, SampleString};
 
 use net_copy::{
-  cli::Cli,
-  config::{Config, Mode},
-  proxy::{Proxy, ProxyConsumer},
+  cli::{Cli, Command},
+  client::Client,
+  config::{ArchiveFormat, Config, Mode},
+  iface,
+  proxy::{Proxy, ProxyConsumer, ProxyMaster},
+  rate,
   recv::Recv,
+  secure,
   send::Send,
+  tls::Tls,
 };
 
 fn main() {
   let cli = Cli::parse();
 
-  let mut config = Config::new(&cli);
+  let mut config = match Config::new(&cli) {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{}", e);
+      return;
+    }
+  };
+  rate::limit(
+    config.limit_rate.map(|rate| rate.0),
+    config.limit_rate_per_conn.map(|rate| rate.0),
+  );
 
-  let mut ip_list: Vec<IpAddr> = vec![];
-  let interfaces = default_net::get_interfaces();
-  for interface in &interfaces {
-    if interface.ipv4.len() > 0 {
-      for net in &interface.ipv4 {
-        if net.addr.is_loopback() || net.addr.is_broadcast() {
-          continue;
-        }
-        ip_list.push(IpAddr::V4(net.addr));
-        println!(
-          "{}: <{}> {}",
-          ip_list.len(),
-          interface.friendly_name.as_ref().unwrap_or(&interface.name),
-          net.addr
-        );
-      }
+  match &cli.command {
+    Some(Command::Get { target, tls }) => {
+      Client::get(
+        target,
+        config.output_dir.unwrap_or_default(),
+        tls.fingerprint.as_deref(),
+        tls.insecure,
+      );
+      return;
     }
-  }
-  for interface in &interfaces {
-    if interface.ipv6.len() > 0 {
-      for net in &interface.ipv6 {
-        if net.addr.is_loopback() {
-          continue;
-        }
-        ip_list.push(IpAddr::V6(net.addr));
-        println!(
-          "{}: <{}> [{}]",
-          ip_list.len(),
-          interface.friendly_name.as_ref().unwrap_or(&interface.name),
-          net.addr
-        );
-      }
+    Some(Command::Put { target, files, tls }) => {
+      Client::put(target, files, tls.fingerprint.as_deref(), tls.insecure);
+      return;
     }
+    None => {}
   }
 
-  if ip_list.is_empty() {
-    println!("Cannot find any valid network interface");
-    return;
-  }
+  let host = if let Some(bind) = config.bind {
+    bind
+  } else if let Some(host) = config.host {
+    host
+  } else if let Some(name) = &config.interface {
+    match iface::by_name(name) {
+      Some(candidate) => {
+        eprintln!("Using {}", candidate);
+        candidate.addr
+      }
+      None => {
+        eprintln!("Cannot find any valid address on network interface {}", name);
+        return;
+      }
+    }
+  } else if io::stdin().is_terminal() {
+    let candidates = iface::candidates();
+    if candidates.is_empty() {
+      eprintln!("Cannot find any valid network interface");
+      return;
+    }
+    for (i, candidate) in candidates.iter().enumerate() {
+      eprintln!("{}: {}", i + 1, candidate);
+    }
 
-  print!("Please choose one in 1..{}: ", ip_list.len());
-  io::stdout().flush().unwrap();
-  let mut input = String::new();
-  let ip_index = match std::io::stdin().read_line(&mut input) {
-    Ok(_) => match input.trim().parse::<usize>() {
-      Ok(value) => {
-        if value < 1 || value > ip_list.len() {
-          println!("Index range is 1..{}", ip_list.len());
+    eprint!("Please choose one in 1..{}: ", candidates.len());
+    io::stderr().flush().unwrap();
+    let mut input = String::new();
+    let ip_index = match std::io::stdin().read_line(&mut input) {
+      Ok(_) => match input.trim().parse::<usize>() {
+        Ok(value) => {
+          if value < 1 || value > candidates.len() {
+            eprintln!("Index range is 1..{}", candidates.len());
+            return;
+          } else {
+            value - 1
+          }
+        }
+        Err(e) => {
+          eprintln!("Parse input as integer failed: {}", e);
           return;
-        } else {
-          value - 1
         }
-      }
+      },
       Err(e) => {
-        println!("Parse input as integer failed: {}", e);
+        eprintln!("Read line failed: {}", e);
+        return;
+      }
+    };
+    config.host = Some(candidates[ip_index].addr);
+    candidates[ip_index].addr
+  } else {
+    match iface::auto() {
+      Some(candidate) => {
+        eprintln!("Using {}", candidate);
+        candidate.addr
+      }
+      None => {
+        eprintln!("Cannot find any valid network interface, please specify --host or --bind");
         return;
       }
-    },
-    Err(e) => {
-      println!("Read line failed: {}", e);
-      return;
     }
   };
 
-  config.host = Some(ip_list[ip_index]);
-
   if config.prompt_save_config {
     config.save();
   }
@@ -100,37 +131,117 @@ fn main() {
       panic!("Pick unused port failed");
     }
   });
-  let socket: SocketAddr = match config.host.unwrap() {
+  let socket: SocketAddr = match host {
     IpAddr::V4(addr) => SocketAddr::V4(SocketAddrV4::new(addr, port)),
     IpAddr::V6(addr) => SocketAddr::V6(SocketAddrV6::new(addr, port, 0, 0)),
   };
   let reserve = config.reserve;
   let proxy_servers = config.proxy.unwrap_or_default();
-  let proxy = if config.no_proxy {
+  let proxy_tokens = config.proxy_token.unwrap_or_default();
+  if config.secure && config.tls {
+    eprintln!("The secure mode cannot be used with TLS, ncp get/put encrypts by itself");
+    return;
+  }
+  let tls = if config.tls {
+    match Tls::new(config.tls_cert.as_deref(), config.tls_key.as_deref()) {
+      Ok(tls) => Some(Arc::new(tls)),
+      Err(e) => {
+        eprintln!("{}", e);
+        return;
+      }
+    }
+  } else {
+    None
+  };
+  let mut proxy = if config.no_proxy {
     None
   } else {
-    ProxyConsumer::try_get(&proxy_servers, &key)
+    ProxyConsumer::try_get(&proxy_servers, &key, proxy_tokens.first())
   };
+  if tls.is_some()
+    && let Some(consumer) = &proxy
+    && consumer.tls.is_none()
+  {
+    eprintln!("WARNING: The proxy does not serve HTTPS, it will not be used");
+    ProxyMaster::end_proxy(&consumer.key, &consumer.master_stream);
+    proxy = None;
+  }
+  if config.secure
+    && let Some(consumer) = &proxy
+    && consumer.tls.is_some()
+  {
+    eprintln!("WARNING: The proxy serves HTTPS which ncp get/put does not speak, it will not be used");
+    ProxyMaster::end_proxy(&consumer.key, &consumer.master_stream);
+    proxy = None;
+  }
+  // A proxy requiring tokens publishes the key under the namespace of the token
+  let key = proxy.as_ref().map_or(key, |consumer| consumer.key.clone());
+  if config.secure && matches!(mode, Mode::Normal) {
+    secure::enable();
+  }
 
+  let is_stdin = cli.files.iter().any(|file| file.as_os_str() == "-");
+  if is_stdin && cli.files.len() > 1 {
+    eprintln!("Stdin (`-`) cannot be sent along with other files");
+    return;
+  }
+  if (is_stdin || cli.stdout) && config.secure {
+    eprintln!("Stdin and stdout cannot be used in the secure mode");
+    return;
+  }
   cli.files.iter().for_each(|file| {
-    if !file.exists() {
+    if !is_stdin && !file.exists() {
       panic!("File not found: {}", file.display());
     }
   });
 
+  if is_stdin {
+    Send::read_stdin_as(cli.name);
+  }
+  if cli.files.is_empty() {
+    if cli.max_downloads.is_some() {
+      eprintln!("WARNING: --max-downloads only applies to senders, it will be ignored");
+    }
+    Recv::stop_after(if cli.once { Some(1) } else { cli.max_uploads }, cli.expire);
+  } else {
+    if cli.max_uploads.is_some() {
+      eprintln!("WARNING: --max-uploads only applies to receivers, it will be ignored");
+    }
+    Send::stop_after(if cli.once { Some(1) } else { cli.max_downloads }, cli.expire);
+  }
+  if cli.stdout {
+    Recv::write_to_stdout();
+  }
+
   match mode {
     Mode::Normal => {
       if cli.files.is_empty() {
-        Recv::run(&key, socket, reserve, proxy, config.auto_rename);
+        Recv::run(
+          &key,
+          socket,
+          reserve,
+          proxy,
+          config.auto_rename,
+          config.output_dir.unwrap_or_default(),
+          tls,
+        );
       } else {
-        Send::run(&key, socket, proxy, cli.files);
+        Send::run(
+          &key,
+          socket,
+          proxy,
+          cli.files,
+          config.scratch_dir.unwrap_or_else(env::temp_dir),
+          config.archive_format.unwrap_or(ArchiveFormat::Tar),
+          tls,
+        );
       }
     }
     Mode::Proxy => {
       if !cli.files.is_empty() {
-        println!("WARNING: The proxy mode has activated, files will be ignored");
+        eprintln!("WARNING: The proxy mode has activated, files will be ignored");
       }
-      Proxy::run(socket);
+      Proxy::run(socket, tls, proxy_tokens, config.control_addr.unwrap_or_default());
     }
   }
 }
@@ -0,0 +1,428 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  io::{self, Read, Write},
+  net::{Shutdown, SocketAddr, TcpStream},
+  sync::{
+    Arc, Condvar, Mutex, MutexGuard,
+    atomic::{AtomicU64, Ordering},
+  },
+};
+
+/// Frames are `<kind: u8> <stream id: u64> <length: u32> <payload>`, big endian
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+/// Lets the peer send as many more bytes on the stream as given by the payload
+const WINDOW: u8 = 2;
+/// No more data on the stream from this side
+const CLOSE: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+/// Releases the key of the connection given by the payload
+const END: u8 = 6;
+
+/// Bytes a side may send on a stream before the peer has read them
+const INITIAL_WINDOW: u32 = 256 * 1024;
+const MAX_FRAME: usize = 16 * 1024;
+
+/// What the reader of a [`Mux`] has to act on, data frames are handled by itself
+pub enum Event {
+  Open(MuxStream),
+  Ping,
+  Pong,
+  End(String),
+}
+
+#[derive(Default)]
+struct ChannelState {
+  recv: VecDeque<u8>,
+  /// The peer has closed the stream, what is left in `recv` is still read but writes fail
+  recv_closed: bool,
+  /// Bytes read but not yet given back to the peer as window
+  unacked: u32,
+  send_window: u32,
+  /// The connection has been lost
+  reset: bool,
+}
+
+#[derive(Default)]
+struct Channel {
+  state: Mutex<ChannelState>,
+  changed: Condvar,
+}
+
+/// Streams multiplexed over one TCP connection with flow control, the proxy master opens streams and the consumer
+/// accepts them
+pub struct Mux {
+  writer: Mutex<TcpStream>,
+  /// Control frames waiting for the writer, whoever holds it writes them before releasing it
+  pending: Mutex<Vec<u8>>,
+  peer_addr: SocketAddr,
+  channels: Mutex<HashMap<u64, Arc<Channel>>>,
+  /// The id of the next stream opened by this side
+  next_id: AtomicU64,
+}
+
+impl Mux {
+  /// Frames are written to `writer`, while the owner of the other half reads them with [`Self::next_event`]
+  pub fn new(writer: TcpStream) -> io::Result<Arc<Self>> {
+    Ok(Arc::new(Self {
+      peer_addr: writer.peer_addr()?,
+      writer: Mutex::new(writer),
+      pending: Mutex::new(vec![]),
+      channels: Mutex::new(HashMap::new()),
+      next_id: AtomicU64::new(0),
+    }))
+  }
+
+  fn frame(kind: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(13 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  fn send_frame(&self, kind: u8, id: u64, payload: &[u8]) -> io::Result<()> {
+    self.write(self.writer.lock().unwrap(), Self::frame(kind, id, payload))
+  }
+
+  /// Send a frame without waiting for the writer, when a data frame of another stream is being written the frame is
+  /// left to that writer. The reader answers pings this way, it must not stall behind a peer that does not read
+  fn send_control(&self, kind: u8, id: u64, payload: &[u8]) -> io::Result<()> {
+    self.pending.lock().unwrap().extend(Self::frame(kind, id, payload));
+    match self.writer.try_lock() {
+      Ok(writer) => self.write(writer, vec![]),
+      Err(_) => Ok(()),
+    }
+  }
+
+  /// Write `frames` and then the pending control frames, the writer is released only once none are left
+  fn write(&self, mut writer: MutexGuard<TcpStream>, mut frames: Vec<u8>) -> io::Result<()> {
+    loop {
+      let result = writer.write_all(&frames).and_then(|_| writer.flush());
+      // A frame written in part leaves the connection unusable, its reader fails and resets all streams
+      if result.is_err() {
+        let _ = writer.shutdown(Shutdown::Both);
+        return result;
+      }
+      let mut pending = self.pending.lock().unwrap();
+      if pending.is_empty() {
+        // Released while the queue is still locked, so a control frame queued after the check finds the writer free
+        drop(writer);
+        return Ok(());
+      }
+      frames = std::mem::take(&mut *pending);
+    }
+  }
+
+  fn add_channel(self: &Arc<Self>, id: u64) -> MuxStream {
+    let channel = Arc::new(Channel::default());
+    channel.state.lock().unwrap().send_window = INITIAL_WINDOW;
+    self.channels.lock().unwrap().insert(id, Arc::clone(&channel));
+    MuxStream {
+      mux: Arc::clone(self),
+      id,
+      channel,
+    }
+  }
+
+  /// Open a new stream to the peer
+  pub fn open(self: &Arc<Self>) -> io::Result<MuxStream> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let stream = self.add_channel(id);
+    self.send_frame(OPEN, id, &[])?;
+    Ok(stream)
+  }
+
+  pub fn ping(&self) -> io::Result<()> {
+    self.send_control(PING, 0, &[])
+  }
+
+  pub fn pong(&self) -> io::Result<()> {
+    self.send_control(PONG, 0, &[])
+  }
+
+  pub fn end(&self, key: &str) -> io::Result<()> {
+    self.send_frame(END, 0, key.as_bytes())
+  }
+
+  /// Read frames from `reader` until one needs the caller, a timeout before a frame starts is returned as is
+  pub fn next_event<R: Read>(self: &Arc<Self>, reader: &mut R) -> io::Result<Event> {
+    loop {
+      let mut kind = [0];
+      reader.read_exact(&mut kind).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"),
+        _ => e,
+      })?;
+      let mut header = [0; 12];
+      let mut payload = vec![];
+      let result = reader.read_exact(&mut header).and_then(|_| {
+        payload.resize(u32::from_be_bytes(header[8..].try_into().unwrap()) as usize, 0);
+        if payload.len() > MAX_FRAME {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+        }
+        reader.read_exact(&mut payload)
+      });
+      // A timeout in the middle of a frame cannot be resumed
+      if let Err(e) = result {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+      }
+      let id = u64::from_be_bytes(header[..8].try_into().unwrap());
+      match kind[0] {
+        OPEN => return Ok(Event::Open(self.add_channel(id))),
+        PING => return Ok(Event::Ping),
+        PONG => return Ok(Event::Pong),
+        END => return Ok(Event::End(String::from_utf8_lossy(&payload).to_string())),
+        DATA | WINDOW | CLOSE => {
+          // Frames of streams dropped on this side are ignored
+          let Some(channel) = self.channels.lock().unwrap().get(&id).cloned() else {
+            continue;
+          };
+          let mut state = channel.state.lock().unwrap();
+          match kind[0] {
+            DATA => {
+              if state.recv.len() + payload.len() > INITIAL_WINDOW as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "window exceeded"));
+              }
+              state.recv.extend(payload);
+            }
+            WINDOW => {
+              let increment = payload
+                .get(..4)
+                .map_or(0, |bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+              state.send_window = state.send_window.saturating_add(increment);
+            }
+            _ => state.recv_closed = true,
+          }
+          channel.changed.notify_all();
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame")),
+      }
+    }
+  }
+
+  /// Close all streams after the peer has ended, the data they received is still read
+  pub fn close(&self) {
+    self.close_channels(false);
+  }
+
+  /// Fail all streams after the connection has been lost
+  pub fn shutdown(&self) {
+    self.close_channels(true);
+  }
+
+  fn close_channels(&self, reset: bool) {
+    for (_, channel) in self.channels.lock().unwrap().drain() {
+      let mut state = channel.state.lock().unwrap();
+      state.reset = reset;
+      state.recv_closed = true;
+      channel.changed.notify_all();
+    }
+  }
+}
+
+/// A stream of a [`Mux`], the peer reads end of stream once it is dropped
+pub struct MuxStream {
+  mux: Arc<Mux>,
+  id: u64,
+  channel: Arc<Channel>,
+}
+
+impl MuxStream {
+  /// The peer of the underlying connection
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+    Ok(self.mux.peer_addr)
+  }
+}
+
+impl Read for MuxStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let mut state = self.channel.state.lock().unwrap();
+    while state.recv.is_empty() && !state.recv_closed {
+      state = self.channel.changed.wait(state).unwrap();
+    }
+    if state.recv.is_empty() {
+      return match state.reset {
+        true => Err(io::Error::new(io::ErrorKind::ConnectionReset, "proxy connection lost")),
+        false => Ok(0),
+      };
+    }
+    let n = state.recv.len().min(buf.len());
+    for (byte, value) in buf.iter_mut().zip(state.recv.drain(..n)) {
+      *byte = value;
+    }
+    state.unacked += n as u32;
+    // Give the window back in batches, not a frame per read
+    if state.unacked >= INITIAL_WINDOW / 2 {
+      let increment = std::mem::take(&mut state.unacked);
+      drop(state);
+      self.mux.send_control(WINDOW, self.id, &increment.to_be_bytes())?;
+    }
+    Ok(n)
+  }
+}
+
+impl Write for MuxStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let mut state = self.channel.state.lock().unwrap();
+    while state.send_window == 0 && !state.recv_closed {
+      state = self.channel.changed.wait(state).unwrap();
+    }
+    if state.reset {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "proxy connection lost"));
+    }
+    if state.recv_closed {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed by peer"));
+    }
+    let n = buf.len().min(state.send_window as usize).min(MAX_FRAME);
+    state.send_window -= n as u32;
+    drop(state);
+    self.mux.send_frame(DATA, self.id, &buf[..n])?;
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Drop for MuxStream {
+  fn drop(&mut self) {
+    self.mux.channels.lock().unwrap().remove(&self.id);
+    if !self.channel.state.lock().unwrap().reset {
+      let _ = self.mux.send_frame(CLOSE, self.id, &[]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::BufReader,
+    net::TcpListener,
+    sync::mpsc::{Receiver, channel},
+    thread,
+    time::Duration,
+  };
+
+  use super::*;
+
+  /// One side of a loopback connection and the events it reads, passed on by a thread
+  type Side = (Arc<Mux>, Receiver<Event>);
+
+  fn side(stream: TcpStream) -> Side {
+    let mux = Mux::new(stream.try_clone().unwrap()).unwrap();
+    let (sender, receiver) = channel();
+    let reader_mux = Arc::clone(&mux);
+    thread::spawn(move || {
+      let mut reader = BufReader::new(stream);
+      while let Ok(event) = reader_mux.next_event(&mut reader) {
+        if sender.send(event).is_err() {
+          break;
+        }
+      }
+      reader_mux.shutdown();
+    });
+    (mux, receiver)
+  }
+
+  fn connect() -> (Side, Side) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    (side(stream), side(accepted))
+  }
+
+  fn accept(events: &Receiver<Event>) -> MuxStream {
+    match events.recv_timeout(Duration::from_secs(5)) {
+      Ok(Event::Open(stream)) => stream,
+      _ => panic!("no stream opened"),
+    }
+  }
+
+  /// Write `len` bytes on `stream` in a thread, the result is sent once the write returns
+  fn write_in_thread(mut stream: MuxStream, len: usize) -> Receiver<io::Result<()>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+      let _ = sender.send(stream.write_all(&vec![7; len]));
+    });
+    receiver
+  }
+
+  #[test]
+  fn carries_data_and_close() {
+    let ((master, _), (_, consumer_events)) = connect();
+    let mut stream = master.open().unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut accepted = accept(&consumer_events);
+    accepted.write_all(b"world").unwrap();
+    drop(stream);
+    let mut content = vec![];
+    accepted.read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"hello");
+  }
+
+  #[test]
+  fn resumes_a_blocked_writer_on_window() {
+    let ((master, _), (_, consumer_events)) = connect();
+    let written = write_in_thread(master.open().unwrap(), INITIAL_WINDOW as usize + 1);
+    let mut accepted = accept(&consumer_events);
+    assert!(written.recv_timeout(Duration::from_millis(300)).is_err());
+    let mut buf = vec![0; INITIAL_WINDOW as usize / 2];
+    accepted.read_exact(&mut buf).unwrap();
+    assert!(written.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+    let mut content = vec![];
+    accepted.read_to_end(&mut content).unwrap();
+    assert_eq!(buf.len() + content.len(), INITIAL_WINDOW as usize + 1);
+  }
+
+  #[test]
+  fn fails_a_blocked_writer_on_close() {
+    let ((master, _), (_, consumer_events)) = connect();
+    let written = write_in_thread(master.open().unwrap(), INITIAL_WINDOW as usize + 1);
+    let accepted = accept(&consumer_events);
+    assert!(written.recv_timeout(Duration::from_millis(300)).is_err());
+    drop(accepted);
+    let result = written.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+  }
+
+  #[test]
+  fn defers_control_frames_while_the_writer_is_busy() {
+    let ((_master, master_events), (consumer, _)) = connect();
+    let writer = consumer.writer.lock().unwrap();
+    consumer.pong().unwrap();
+    assert!(master_events.recv_timeout(Duration::from_millis(300)).is_err());
+    consumer.write(writer, vec![]).unwrap();
+    assert!(matches!(
+      master_events.recv_timeout(Duration::from_secs(5)),
+      Ok(Event::Pong)
+    ));
+  }
+
+  #[test]
+  fn rejects_oversized_frames() {
+    let ((master, _), _consumer) = connect();
+    let oversized = Mux::frame(DATA, 0, &vec![0; MAX_FRAME + 1]);
+    let error = master.next_event(&mut &oversized[..]).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn rejects_data_beyond_the_window() {
+    let ((master, _), _consumer) = connect();
+    let stream = master.open().unwrap();
+    let frames = (0..=INITIAL_WINDOW as usize / MAX_FRAME)
+      .flat_map(|_| Mux::frame(DATA, stream.id, &[0; MAX_FRAME]))
+      .collect::<Vec<_>>();
+    let error = master.next_event(&mut &frames[..]).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+}
@@ -1,46 +1,174 @@
 use std::{
-  collections::HashMap,
-  io::{BufRead, Read, Write},
+  collections::{HashMap, hash_map::Entry},
+  io::{self, BufRead, BufReader, Read, Write},
   iter,
-  net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+  net::{Shutdown, SocketAddr, TcpListener, TcpStream},
   str::FromStr,
-  sync::mpsc::{Receiver, Sender, channel},
+  sync::{
+    Arc, Mutex,
+    mpsc::{Receiver, Sender, channel},
+  },
   thread,
-  time::Duration,
+  time::{Duration, Instant},
 };
 
 use bufstream::BufStream;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
 
-struct ListenerEvent {
-  sender: Sender<(String, TcpStream)>,
-  receiver: Receiver<String>,
+use crate::{
+  config::{ProxyAddr, ProxyToken},
+  digest, http, iface,
+  mux::{Event, Mux, MuxStream},
+  stream::Stream,
+  tls::{Tls, TlsInfo},
+};
+
+/// The control ports of the proxy master, the second is used when the first is taken
+pub const CONTROL_PORTS: [u16; 2] = [7070, 7575];
+/// How often the proxy master pings a silent registered connection, its key is removed after 3 pings without a reply
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a new control connection may take to send its command
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a write to a control connection may block, a consumer not reading it is dropped after that
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a request of the public listener waits for the consumer to connect back
+const TRANSPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a consumer keeps trying to register again after losing the proxy master
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The key registered by this consumer and a handle of its connection, to release it on exit
+static REGISTERED: Mutex<Option<Registration>> = Mutex::new(None);
+
+/// The key, the connection that registered it and its multiplexer if any
+type Registration = (String, TcpStream, Option<Arc<Mux>>);
+
+/// A registered consumer, `REQUEST` and `PING` are written to its control connection unless it is multiplexed
+struct Master {
+  /// Locked on its own, a consumer slow to read only holds up the writers to it
+  stream: Arc<Mutex<TcpStream>>,
+  /// Tells the registration apart from a later one of the same key
+  id: u64,
+  mux: Option<Arc<Mux>>,
+}
+
+/// The state shared by the control and the public connections, every connection is served by a thread of its own
+#[derive(Default)]
+struct Registry {
+  masters: Mutex<HashMap<String, Master>>,
+  /// Public connections waiting for their transport stream by correlation id, along with the key they asked for
+  pending: Mutex<HashMap<u64, (String, Sender<TcpStream>)>>,
+}
+
+impl Registry {
+  /// Ids are random, a client cannot guess the correlation id of another one to take its transport
+  fn next_id(&self) -> u64 {
+    rand::random()
+  }
+
+  /// Wait for a transport stream of `key` under a new correlation id
+  fn add_pending(&self, key: &str) -> (u64, Receiver<TcpStream>) {
+    let (sender, receiver) = channel();
+    let mut pending = self.pending.lock().unwrap();
+    loop {
+      let id = self.next_id();
+      if let Entry::Vacant(entry) = pending.entry(id) {
+        entry.insert((key.to_string(), sender));
+        return (id, receiver);
+      }
+    }
+  }
+
+  /// Remove `key` if it is still the registration `id`
+  fn remove(&self, key: &str, id: u64, reason: &str) {
+    let mut masters = self.masters.lock().unwrap();
+    if masters.get(key).is_some_and(|master| master.id == id) {
+      masters.remove(key);
+      eprintln!("The key {} removed ({})", key, reason);
+      eprintln!("Left nodes: {}", masters.len());
+    }
+  }
+
+  /// Write `message` to the control connection of `key` (of the registration `id` if given), `false` if there is no
+  /// such registration or it has been removed for failing
+  fn notify(&self, key: &str, id: Option<u64>, message: &str) -> bool {
+    let Some((stream, id)) = self
+      .masters
+      .lock()
+      .unwrap()
+      .get(key)
+      .filter(|master| id.is_none_or(|id| id == master.id))
+      .map(|master| (Arc::clone(&master.stream), master.id))
+    else {
+      return false;
+    };
+    let mut stream = stream.lock().unwrap();
+    if let Err(e) = stream
+      .write_all(format!("{}\r\n\r\n", message).as_bytes())
+      .and_then(|_| stream.flush())
+    {
+      eprintln!("Write to the master of {} failed: {}", key, e);
+      drop(stream);
+      self.remove(key, id, "connection failed");
+      return false;
+    }
+    true
+  }
 }
 
-struct MasterEvent {
-  sender: Sender<String>,
-  receiver: Receiver<(String, TcpStream)>,
+/// The back-connection of a public connection, a connection of its own or a stream multiplexed over the control one
+enum Transport {
+  Tcp(TcpStream),
+  Mux(MuxStream),
+}
+
+impl Transport {
+  fn peer_addr(&self) -> io::Result<SocketAddr> {
+    match self {
+      Self::Tcp(stream) => stream.peer_addr(),
+      Self::Mux(stream) => stream.peer_addr(),
+    }
+  }
+}
+
+impl Read for Transport {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Self::Tcp(stream) => stream.read(buf),
+      Self::Mux(stream) => stream.read(buf),
+    }
+  }
+}
+
+impl Write for Transport {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Self::Tcp(stream) => stream.write(buf),
+      Self::Mux(stream) => stream.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Self::Tcp(stream) => stream.flush(),
+      Self::Mux(stream) => stream.flush(),
+    }
+  }
 }
 
 pub struct Proxy {}
 
 impl Proxy {
-  pub fn run(socket: SocketAddr) {
-    let (stream_sender, stream_receiver) = channel::<(String, TcpStream)>();
-    let (key_sender, key_receiver) = channel::<String>();
-    let mut proxy_master = ProxyMaster::new(
+  pub fn run(socket: SocketAddr, tls: Option<Arc<Tls>>, tokens: Vec<ProxyToken>, control_addrs: Vec<SocketAddr>) {
+    let registry = Arc::new(Registry::default());
+    let proxy_master = Arc::new(ProxyMaster::new(
       socket,
-      ListenerEvent {
-        sender: stream_sender,
-        receiver: key_receiver,
-      },
-    );
-    let proxy_listener = ProxyListener::new(
-      socket,
-      MasterEvent {
-        sender: key_sender,
-        receiver: stream_receiver,
-      },
-    );
+      tls.as_ref().map(|tls| tls.info.clone()),
+      tokens,
+      control_addrs,
+      Arc::clone(&registry),
+    ));
+    let proxy_listener = Arc::new(ProxyListener::new(socket, tls, registry));
     thread::spawn(move || {
       proxy_master.run();
     });
@@ -48,228 +176,584 @@ impl Proxy {
   }
 }
 
+/// How the end of a relayed message body is found
+enum Body {
+  Length(u64),
+  Chunked,
+  UntilClose,
+}
+
+impl Body {
+  /// The framing announced by `headers`, `None` if there is neither `Content-Length` nor chunked encoding
+  fn from_headers(headers: &[String]) -> Result<Option<Self>, String> {
+    if http::is_chunked(headers) {
+      return Ok(Some(Self::Chunked));
+    }
+    match http::header(headers, "Content-Length").map(|value| value.parse::<u64>()) {
+      Some(Ok(length)) => Ok(Some(Self::Length(length))),
+      Some(Err(e)) => Err(format!("Parse content length from header failed: {}", e)),
+      None => Ok(None),
+    }
+  }
+}
+
+/// Read a control message, a single line followed by an empty one
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<String> {
+  let mut message = String::new();
+  if reader.read_line(&mut message)? == 0 {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+  }
+  let mut line = String::new();
+  reader.read_line(&mut line)?;
+  if line != "\r\n" {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad message"));
+  }
+  Ok(message.trim().to_string())
+}
+
+/// The proof of knowing the token secret for a control command, an HMAC-SHA256 over the nonce given by the proxy master
+fn sign(secret: &str, nonce: &str, command: &str) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+  mac.update(format!("{} {}", nonce, command).as_bytes());
+  digest::to_hex(&mac.finalize().into_bytes())
+}
+
+/// Write a control command like `PROXY <key>`, signed with `token` after asking the proxy master for a nonce
+fn write_command(buf_stream: &mut BufStream<TcpStream>, command: &str, token: Option<&ProxyToken>) -> io::Result<()> {
+  let Some(token) = token else {
+    buf_stream.write_all(format!("{}\r\n\r\n", command).as_bytes())?;
+    return buf_stream.flush();
+  };
+  buf_stream.write_all(b"NONCE\r\n\r\n")?;
+  buf_stream.flush()?;
+  let reply = read_message(buf_stream)?;
+  let nonce = match reply.split_once(' ') {
+    Some(("NONCE", nonce)) => nonce,
+    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no nonce from proxy master")),
+  };
+  let signature = sign(&token.secret, nonce, command);
+  buf_stream.write_all(format!("{} {} {}\r\n\r\n", command, token.name, signature).as_bytes())?;
+  buf_stream.flush()
+}
+
 pub struct ProxyMaster {
-  masters: HashMap<String, TcpStream>,
   listener_socket: SocketAddr,
-  listener_event: ListenerEvent,
+  listener_tls: Option<TlsInfo>,
+  /// Secrets by token name, everyone may register when empty
+  tokens: HashMap<String, String>,
+  /// Every address is listened on, the default control ports are tried in turn when empty
+  control_addrs: Vec<SocketAddr>,
+  registry: Arc<Registry>,
 }
 
 impl ProxyMaster {
-  fn new(listener_socket: SocketAddr, listener_event: ListenerEvent) -> Self {
+  fn new(
+    listener_socket: SocketAddr, listener_tls: Option<TlsInfo>, tokens: Vec<ProxyToken>,
+    control_addrs: Vec<SocketAddr>, registry: Arc<Registry>,
+  ) -> Self {
     Self {
-      masters: HashMap::new(),
       listener_socket,
-      listener_event,
+      listener_tls,
+      tokens: tokens.into_iter().map(|token| (token.name, token.secret)).collect(),
+      control_addrs,
+      registry,
     }
   }
 
-  fn proxy_handle(&mut self, stream: TcpStream) {
+  /// Check the signature of a command of `args` words followed by `<token name> <signature>`, the token name is
+  /// returned, `None` if the proxy is open
+  fn authorize<'a>(&self, chunks: &[&'a str], args: usize, nonce: Option<&str>) -> Result<Option<&'a str>, String> {
+    if self.tokens.is_empty() {
+      return Ok(None);
+    }
+    let (Some(nonce), [name, signature]) = (nonce, chunks.get(args..).unwrap_or_default()) else {
+      return Err("Unauthenticated cmd".to_string());
+    };
+    let Some(secret) = self.tokens.get(*name) else {
+      return Err(format!("Unknown token {}", name));
+    };
+    let expected = sign(secret, nonce, &chunks[..args].join(" "));
+    // Compare in constant time, so the signature cannot be guessed byte by byte
+    let diff = expected
+      .bytes()
+      .zip(signature.bytes())
+      .fold(expected.len() ^ signature.len(), |diff, (a, b)| diff | (a ^ b) as usize);
+    if diff != 0 {
+      return Err(format!("Bad signature for token {}", name));
+    }
+    Ok(Some(name))
+  }
+
+  fn proxy_handle(&self, stream: TcpStream) {
     let target_socket = match stream.peer_addr() {
       Ok(socket) => socket,
       Err(e) => {
-        println!("Get peer socket failed: {}", e);
+        eprintln!("Get peer socket failed: {}", e);
         return;
       }
     };
-    let mut buf_stream = BufStream::new(stream);
-    let mut request = String::new();
-    if let Err(e) = buf_stream.read_line(&mut request) {
-      println!("Read from {} failed: {}", target_socket, e);
+    // A client that never finishes its command only holds its own thread
+    if let Err(e) = stream.set_read_timeout(Some(CONTROL_TIMEOUT)) {
+      eprintln!("Set read timeout for stream failed: {}", e);
       return;
     }
-    let mut line = String::new();
-    match buf_stream.read_line(&mut line) {
-      Ok(_) => {
-        if line != "\r\n" {
-          println!("Bad request from {}", target_socket);
-          return;
-        }
-      }
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+      eprintln!("Set write timeout for stream failed: {}", e);
+      return;
+    }
+    let mut buf_stream = BufStream::new(stream);
+    let mut request = match read_message(&mut buf_stream) {
+      Ok(request) => request,
       Err(e) => {
-        println!("Read from {} failed: {}", target_socket, e);
+        eprintln!("Read from {} failed: {}", target_socket, e);
         return;
       }
+    };
+    // Signed commands follow a nonce on the same connection, so a signature cannot be replayed
+    let mut nonce = None;
+    if request == "NONCE" {
+      let value = digest::to_hex(&rand::random::<[u8; 16]>());
+      if let Err(e) = buf_stream
+        .write_all(format!("NONCE {}\r\n\r\n", value).as_bytes())
+        .and_then(|_| buf_stream.flush())
+      {
+        eprintln!("Write nonce to {} failed: {}", target_socket, e);
+        return;
+      }
+      request = match read_message(&mut buf_stream) {
+        Ok(request) => request,
+        Err(e) => {
+          eprintln!("Read from {} failed: {}", target_socket, e);
+          return;
+        }
+      };
+      nonce = Some(value);
     }
 
     let chunks: Vec<_> = request.split_whitespace().collect();
     if chunks.is_empty() {
-      println!("Bad request from {}", target_socket);
+      eprintln!("Bad request from {}", target_socket);
       return;
     }
     match chunks[0] {
       "PING" => {
         if let Err(e) = buf_stream.write_all(b"PONG\r\n\r\n").and_then(|_| buf_stream.flush()) {
-          println!("Write `PONG` to {} failed: {}", target_socket, e);
+          eprintln!("Write `PONG` to {} failed: {}", target_socket, e);
         }
       }
-      "PROXY" => {
-        if let Err(e) = buf_stream
-          .write_all(format!("{}\r\n\r\n", self.listener_socket).as_bytes())
-          .and_then(|_| buf_stream.flush())
-        {
-          println!("Write listener socket to {} failed: {}", target_socket, e);
-        } else if chunks.len() < 2 {
-          println!("Wrong cmd from {}", target_socket);
-        } else if self.masters.contains_key(chunks[1]) {
-          println!("The key {} exists", chunks[1]);
+      // `MUX` registers like `PROXY`, after the reply the connection carries the transports as multiplexed frames
+      "PROXY" | "MUX" => {
+        if chunks.len() < 2 {
+          eprintln!("Wrong cmd from {}", target_socket);
+          return;
+        }
+        // Keys of a token live in its namespace, so token holders cannot squat each other's keys
+        let key = match self.authorize(&chunks, 2, nonce.as_deref()) {
+          Ok(Some(name)) => format!("{}.{}", name, chunks[1]),
+          Ok(None) => chunks[1].to_string(),
+          Err(e) => {
+            eprintln!("{} from {}", e, target_socket);
+            Self::reply_error(&mut buf_stream, &e);
+            return;
+          }
+        };
+        let stream = match buf_stream.get_ref().try_clone() {
+          Ok(stream) => stream,
+          Err(e) => {
+            eprintln!("Clone the stream of {} failed: {}", target_socket, e);
+            return;
+          }
+        };
+        let mux = if chunks[0] == "MUX" {
+          match stream.try_clone().and_then(Mux::new) {
+            Ok(mux) => Some(mux),
+            Err(e) => {
+              eprintln!("Multiplex the stream of {} failed: {}", target_socket, e);
+              return;
+            }
+          }
         } else {
-          self
-            .masters
-            .insert(chunks[1].to_string(), buf_stream.into_inner().unwrap());
+          None
+        };
+        let id = self.registry.next_id();
+        {
+          // Held until the reply is written, so no `REQUEST` can come before it
+          let mut masters = self.registry.masters.lock().unwrap();
+          if masters.contains_key(&key) {
+            eprintln!("The key {} exists", key);
+            Self::reply_error(&mut buf_stream, &format!("The key {} exists", key));
+            return;
+          }
+          let mut reply = format!(
+            "{} key={} heartbeat={}",
+            self.listener_socket,
+            key,
+            HEARTBEAT_INTERVAL.as_secs()
+          );
+          if let Some(tls) = &self.listener_tls {
+            reply.push_str(&format!(" fingerprint={}", tls.fingerprint));
+            if let Some(pin) = &tls.pinned_pubkey {
+              reply.push_str(&format!(" pin={}", pin));
+            }
+          }
+          if let Err(e) = buf_stream
+            .write_all(format!("{}\r\n\r\n", reply).as_bytes())
+            .and_then(|_| buf_stream.flush())
+          {
+            eprintln!("Write listener socket to {} failed: {}", target_socket, e);
+            return;
+          }
+          masters.insert(
+            key.clone(),
+            Master {
+              stream: Arc::new(Mutex::new(stream)),
+              id,
+              mux: mux.clone(),
+            },
+          );
+          eprintln!("The key {} registered by {}", key, target_socket);
         }
+        self.supervise(&key, id, buf_stream, mux);
       }
       "SEND" | "RECV" => {
-        if chunks.len() < 2 {
-          println!("Wrong cmd from {}", target_socket);
-        } else if !self.masters.contains_key(chunks[1]) {
-          println!("The key {} doesn't exist", chunks[1]);
-        } else if let Err(e) = self
-          .listener_event
-          .sender
-          .send((chunks[1].to_string(), buf_stream.into_inner().unwrap()))
+        if chunks.len() < 3 {
+          eprintln!("Wrong cmd from {}", target_socket);
+          return;
+        }
+        match self.authorize(&chunks, 3, nonce.as_deref()) {
+          Ok(Some(name)) if !chunks[1].starts_with(&format!("{}.", name)) => {
+            eprintln!(
+              "The key {} is not owned by token {}, from {}",
+              chunks[1], name, target_socket
+            );
+            return;
+          }
+          Ok(_) => {}
+          Err(e) => {
+            eprintln!("{} from {}", e, target_socket);
+            return;
+          }
+        }
+        // The stream only goes to the public connection it was requested for
+        let sender = match chunks[2].parse::<u64>() {
+          Ok(id) => {
+            let mut pending = self.registry.pending.lock().unwrap();
+            match pending.get(&id) {
+              Some((key, _)) if key == chunks[1] => pending.remove(&id).map(|(_, sender)| sender),
+              _ => None,
+            }
+          }
+          Err(_) => None,
+        };
+        let Some(sender) = sender else {
+          eprintln!(
+            "No pending request {} of {} from {}",
+            chunks[2], chunks[1], target_socket
+          );
+          return;
+        };
+        let stream = buf_stream.into_inner().unwrap();
+        if let Err(e) = stream
+          .set_read_timeout(None)
+          .and_then(|_| stream.set_write_timeout(None))
         {
-          println!("Send TCP stream failed: {}", e);
+          eprintln!("Set timeout for stream failed: {}", e);
+          return;
+        }
+        if sender.send(stream).is_err() {
+          eprintln!("The request {} of {} has timed out", chunks[2], chunks[1]);
         }
       }
       "END" => {
-        if chunks.len() < 2 {
-          println!("Wrong cmd from {}", target_socket);
-        } else if !self.masters.contains_key(chunks[1]) {
-          println!("The key {} doesn't exist", chunks[1]);
-        } else {
-          self.masters.remove(chunks[1]);
-          println!("The key {} removed", chunks[1]);
-          println!("Left nodes: {}", self.masters.len());
-        }
+        eprintln!(
+          "END from {} refused, only the registering connection can end its key",
+          target_socket
+        );
       }
       _ => {
-        println!("Bad cmd from {}", target_socket);
+        eprintln!("Bad cmd from {}", target_socket);
       }
     }
   }
 
-  fn run(&mut self) {
-    let addrs = [
-      SocketAddr::from(([0, 0, 0, 0], 7070)),
-      SocketAddr::from(([0, 0, 0, 0], 7575)),
-    ];
-    let listener = match TcpListener::bind(&addrs[..]) {
-      Ok(listener) => listener,
+  fn reply_error(buf_stream: &mut BufStream<TcpStream>, message: &str) {
+    if let Err(e) = buf_stream
+      .write_all(format!("ERROR {}\r\n\r\n", message).as_bytes())
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write error reply failed: {}", e);
+    }
+  }
+
+  /// Read `PONG` and `END` from the registered connection and ping it when silent, until the key is removed
+  fn supervise(&self, key: &str, id: u64, mut buf_stream: BufStream<TcpStream>, mux: Option<Arc<Mux>>) {
+    if let Err(e) = buf_stream.get_ref().set_read_timeout(Some(HEARTBEAT_INTERVAL)) {
+      eprintln!("Set read timeout for stream failed: {}", e);
+    }
+    let mut missed = 0;
+    let mut ended = false;
+    let reason = loop {
+      let message = match &mux {
+        Some(mux) => mux.next_event(&mut buf_stream).map(|event| match event {
+          Event::Pong => "PONG".to_string(),
+          Event::End(key) => format!("END {}", key),
+          Event::Ping => "PING".to_string(),
+          Event::Open(_) => "OPEN".to_string(),
+        }),
+        None => read_message(&mut buf_stream),
+      };
+      match message {
+        Ok(message) => {
+          missed = 0;
+          match message.split_whitespace().collect::<Vec<_>>()[..] {
+            ["PONG"] => {}
+            ["END", end_key] if end_key == key => {
+              if mux.is_none() {
+                break "ended";
+              }
+              // Open streams may still carry data, they are read until the consumer closes the connection
+              ended = true;
+              self.registry.remove(key, id, "ended");
+            }
+            _ => eprintln!("Bad cmd from the master of {}", key),
+          }
+        }
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+          if missed == 3 {
+            break "heartbeat timed out";
+          }
+          missed += 1;
+          match &mux {
+            Some(mux) => {
+              if mux.ping().is_err() {
+                break "connection failed";
+              }
+            }
+            None => {
+              if !self.registry.notify(key, Some(id), "PING") {
+                return;
+              }
+            }
+          }
+        }
+        Err(_) => break "connection closed",
+      }
+    };
+    match &mux {
+      Some(mux) if ended => mux.close(),
+      Some(mux) => mux.shutdown(),
+      None => {}
+    }
+    self.registry.remove(key, id, reason);
+  }
+
+  fn run(self: Arc<Self>) {
+    let bound = if self.control_addrs.is_empty() {
+      let addrs = CONTROL_PORTS.map(|port| SocketAddr::from(([0, 0, 0, 0], port)));
+      TcpListener::bind(&addrs[..]).map(|listener| vec![listener])
+    } else {
+      self.control_addrs.iter().map(TcpListener::bind).collect()
+    };
+    let listeners = match bound {
+      Ok(listeners) => listeners,
       Err(e) => {
-        println!("Bind failed: {}", e);
+        eprintln!("Bind failed: {}", e);
         return;
       }
     };
 
-    if let Err(e) = listener.set_nonblocking(true) {
-      println!("Set non blocking for listener failed: {}", e);
-      return;
-    }
-
-    loop {
-      if let Ok((stream, _)) = listener.accept() {
-        self.proxy_handle(stream);
-      }
-      if let Ok(key) = self.listener_event.receiver.recv_timeout(Duration::from_millis(100)) {
-        if let Some(master) = self.masters.get_mut(&key) {
-          if let Err(e) = master.write_all(b"REQUEST\r\n\r\n").and_then(|_| master.flush()) {
-            println!(
-              "Write to underlying stream ({}) failed: {}",
-              master.peer_addr().unwrap(),
-              e
-            );
-            continue;
-          }
-        } else {
-          println!("Unknown key: {}", key);
-          continue;
+    let handles = listeners
+      .into_iter()
+      .map(|listener| {
+        if let Ok(addr) = listener.local_addr() {
+          eprintln!("Proxy control listening on {}", addr);
         }
-      }
+        let proxy_master = Arc::clone(&self);
+        thread::spawn(move || {
+          for stream in listener.incoming() {
+            match stream {
+              Ok(stream) => {
+                let proxy_master = Arc::clone(&proxy_master);
+                thread::spawn(move || proxy_master.proxy_handle(stream));
+              }
+              Err(e) => eprintln!("Proxy master get incoming stream failed: {}", e),
+            }
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+    for handle in handles {
+      let _ = handle.join();
     }
   }
 
-  pub fn get_transport_stream(key: &str, master_stream: TcpStream) -> impl iter::Iterator<Item = TcpStream> {
-    let master_socket = master_stream.peer_addr().unwrap();
-    let mut master_buf_stream = BufStream::new(master_stream);
-    let key = key.to_string();
+  /// Accept the streams the proxy master opens over the control connection, or connect back for every `REQUEST` of a
+  /// proxy master without multiplexing, pings are answered and the key is registered again when the connection to the
+  /// proxy master is lost
+  pub fn get_transport_stream(proxy: ProxyConsumer) -> impl iter::Iterator<Item = Stream> {
+    let ProxyConsumer {
+      master_socket,
+      key,
+      request_key,
+      token,
+      heartbeat,
+      mux,
+      ..
+    } = proxy;
+    let (mut master_buf_stream, mut mux) = Self::watch(&key, proxy.master_stream, heartbeat, mux);
     iter::from_fn(move || {
-      let mut request = String::new();
-      if let Err(e) = master_buf_stream.read_line(&mut request) {
-        println!("Read from {} failed: {}", master_socket, e);
-        return None;
-      }
-      let mut line = String::new();
-      match master_buf_stream.read_line(&mut line) {
-        Ok(_) => {
-          if line != "\r\n" {
-            println!("Bad request from {}", master_socket);
+      loop {
+        let result = match &mux {
+          Some(mux) => match mux.next_event(&mut master_buf_stream) {
+            Ok(Event::Open(stream)) => return Some(Stream::Mux(stream)),
+            Ok(Event::Ping) => mux.pong(),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+          },
+          None => match read_message(&mut master_buf_stream) {
+            Ok(request) => match request.split_whitespace().collect::<Vec<_>>()[..] {
+              ["REQUEST", id] => {
+                let mut buf_stream = match TcpStream::connect(master_socket) {
+                  Ok(stream) => BufStream::new(stream),
+                  Err(e) => {
+                    eprintln!("Connect to proxy master failed: {}", e);
+                    continue;
+                  }
+                };
+                if let Err(e) = write_command(&mut buf_stream, &format!("SEND {} {}", key, id), token.as_ref()) {
+                  eprintln!("Write to master stream failed: {}", e);
+                  continue;
+                }
+                return buf_stream.into_inner().ok().map(Stream::Tcp);
+              }
+              ["PING"] => master_buf_stream
+                .write_all(b"PONG\r\n\r\n")
+                .and_then(|_| master_buf_stream.flush()),
+              _ => {
+                eprintln!("Bad request from {}", master_socket);
+                return None;
+              }
+            },
+            Err(e) => Err(e),
+          },
+        };
+
+        if let Err(e) = result {
+          eprintln!("Lost the proxy master {}: {}", master_socket, e);
+          if let Some(mux) = &mux {
+            mux.shutdown();
+          }
+          // A half-open connection would otherwise keep the key on the master and get the END of this process
+          if let Some((_, stream, _)) = REGISTERED.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+          }
+          let consumer = Self::reconnect(master_socket, &request_key, token.as_ref())?;
+          if consumer.key != key {
+            eprintln!("The proxy master registered {} instead of {}", consumer.key, key);
             return None;
           }
+          eprintln!("Registered {} with the proxy master {} again", key, master_socket);
+          (master_buf_stream, mux) = Self::watch(&key, consumer.master_stream, consumer.heartbeat, consumer.mux);
         }
+      }
+    })
+  }
+
+  /// Keep a handle of the registered connection to release `key` on exit, reads time out without pings from a proxy
+  /// master sending heartbeats
+  fn watch(
+    key: &str, master_stream: TcpStream, heartbeat: Option<Duration>, mux: bool,
+  ) -> (BufStream<TcpStream>, Option<Arc<Mux>>) {
+    if let Err(e) = master_stream.set_read_timeout(heartbeat.map(|interval| interval * 3)) {
+      eprintln!("Set read timeout for stream failed: {}", e);
+    }
+    let mux = match mux {
+      true => match master_stream.try_clone().and_then(Mux::new) {
+        Ok(mux) => Some(mux),
         Err(e) => {
-          println!("Read from {} failed: {}", master_socket, e);
-          return None;
+          eprintln!("Multiplex the proxy connection failed: {}", e);
+          None
         }
+      },
+      false => None,
+    };
+    *REGISTERED.lock().unwrap() = master_stream
+      .try_clone()
+      .ok()
+      .map(|stream| (key.to_string(), stream, mux.clone()));
+    (BufStream::new(master_stream), mux)
+  }
+
+  /// Register `key` again with backoff, until [`RECONNECT_TIMEOUT`]
+  fn reconnect(master_socket: SocketAddr, key: &str, token: Option<&ProxyToken>) -> Option<ProxyConsumer> {
+    let deadline = Instant::now() + RECONNECT_TIMEOUT;
+    let mut backoff = Duration::from_secs(1);
+    while Instant::now() < deadline {
+      thread::sleep(backoff);
+      if let Some(consumer) = ProxyConsumer::try_get_one(&[master_socket], key, token) {
+        return Some(consumer);
       }
+      backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+    eprintln!(
+      "Give up the proxy master {} after {:?}",
+      master_socket, RECONNECT_TIMEOUT
+    );
+    None
+  }
 
-      if request.trim() == "REQUEST" {
-        let mut stream = match TcpStream::connect(master_socket) {
-          Ok(stream) => stream,
-          Err(e) => {
-            println!("Connect to proxy master failed: {}", e);
-            return None;
-          }
-        };
-        if let Err(e) = stream
-          .write_all(format!("SEND {}\r\n\r\n", key).as_bytes())
-          .and_then(|_| stream.flush())
-        {
-          println!("Write to master stream failed: {}", e);
-          return None;
+  /// Release the key registered by [`Self::get_transport_stream`], if any
+  pub fn leave() {
+    match &*REGISTERED.lock().unwrap() {
+      Some((key, _, Some(mux))) => {
+        if let Err(e) = mux.end(key) {
+          eprintln!("Send END to proxy master failed: {}", e);
         }
-        Some(stream)
-      } else {
-        None
       }
-    })
+      Some((key, master_stream, None)) => Self::end_proxy(key, master_stream),
+      None => {}
+    }
   }
 
-  pub fn end_proxy(key: &str, socket: SocketAddr) {
-    if let Err(e) = TcpStream::connect(socket).and_then(|mut stream| {
-      stream
-        .write_all(format!("END {}\r\n\r\n", key).as_bytes())
-        .and_then(|_| stream.flush())
-    }) {
-      println!("Send END to proxy master failed: {}", e);
+  /// Release `key`, only accepted on the connection that registered it
+  pub fn end_proxy(key: &str, mut master_stream: &TcpStream) {
+    if let Err(e) = master_stream
+      .write_all(format!("END {}\r\n\r\n", key).as_bytes())
+      .and_then(|_| master_stream.flush())
+    {
+      eprintln!("Send END to proxy master failed: {}", e);
     }
   }
 }
 
 struct ProxyListener {
   socket: SocketAddr,
-  master_event: MasterEvent,
+  tls: Option<Arc<Tls>>,
+  registry: Arc<Registry>,
 }
 
 impl ProxyListener {
-  pub fn new(socket: SocketAddr, master_event: MasterEvent) -> Self {
-    Self { socket, master_event }
+  pub fn new(socket: SocketAddr, tls: Option<Arc<Tls>>, registry: Arc<Registry>) -> Self {
+    Self { socket, tls, registry }
   }
 
-  pub fn run(&self) {
-    let listener = match TcpListener::bind(self.socket) {
+  pub fn run(self: Arc<Self>) {
+    let listener = match iface::listen(self.socket) {
       Ok(listener) => listener,
       Err(e) => {
-        println!("Bind to {} failed: {}", self.socket, e);
+        eprintln!("Bind to {} failed: {}", self.socket, e);
         return;
       }
     };
     for stream in listener.incoming() {
       match stream {
         Ok(stream) => {
-          self.proxy_handle(stream);
+          // The TLS handshake and the wait for the consumer only hold this connection
+          let proxy_listener = Arc::clone(&self);
+          thread::spawn(move || proxy_listener.proxy_handle(stream));
         }
         Err(e) => {
-          println!("Proxy listener get incoming stream failed: {}", e);
+          eprintln!("Proxy listener get incoming stream failed: {}", e);
           continue;
         }
       }
@@ -280,19 +764,25 @@ impl ProxyListener {
     let target_socket = match stream.peer_addr() {
       Ok(socket) => socket,
       Err(e) => {
-        println!("Get peer addr failed: {}", e);
+        eprintln!("Get peer addr failed: {}", e);
+        return;
+      }
+    };
+    let stream = match Stream::new(stream, self.tls.as_deref()) {
+      Ok(stream) => stream,
+      Err(e) => {
+        eprintln!("{}", e);
         return;
       }
     };
     let mut buf_stream = BufStream::new(stream);
     let mut headers = vec![];
     let mut request_method = None;
-    let mut content_length = None;
     let mut key = None;
     loop {
       let mut line = String::new();
       if let Err(e) = buf_stream.read_line(&mut line) {
-        println!("Read data from target stream failed: {}", e);
+        eprintln!("Read data from target stream failed: {}", e);
         return;
       }
       if line == "\r\n" {
@@ -301,286 +791,305 @@ impl ProxyListener {
       if request_method.is_none() {
         let chunks: Vec<_> = line.split_whitespace().collect();
         if chunks.len() < 2 {
-          println!("Bad request from {}", target_socket);
+          eprintln!("Bad request from {}", target_socket);
           return;
         }
         request_method = Some(chunks[0].to_string());
-        key = Some(chunks[1].trim_start_matches('/').to_string());
-      }
-      if content_length.is_none() && request_method.as_ref().unwrap() == "POST" && line.starts_with("Content-Length:") {
-        content_length = match line.trim().split(':').take(2).last() {
-          Some(value) => match value.trim().parse::<usize>() {
-            Ok(v) => Some(v),
-            Err(e) => {
-              println!("Parse content length from header failed: {}", e);
-              return;
-            }
-          },
-          None => {
-            println!("Parse content length from header failed");
-            return;
-          }
-        }
+        // The key is the first path segment, e.g. `/<key>/pake`
+        key = chunks[1]
+          .trim_start_matches('/')
+          .split(['/', '?'])
+          .next()
+          .map(str::to_string);
       }
       headers.push(line);
       if headers.len() > 100 {
-        println!("Too many headers from {}", target_socket);
+        eprintln!("Too many headers from {}", target_socket);
         return;
       }
     }
     let request_method = request_method.unwrap();
+    let is_upload = request_method == "POST" || request_method == "PUT";
+    let request_body = match Body::from_headers(&headers) {
+      Ok(Some(body)) if is_upload => body,
+      Ok(None) if is_upload => {
+        eprintln!("Missing content length from {}", target_socket);
+        return;
+      }
+      Ok(_) => Body::Length(0),
+      Err(e) => {
+        eprintln!("{} from {}", e, target_socket);
+        return;
+      }
+    };
     let key = key.unwrap();
     let underlying_stream = match self.get_transport_stream(&key) {
       Some(stream) => stream,
       None => {
-        println!("Get underlying TCP stream failed");
+        eprintln!("Get underlying TCP stream failed");
         return;
       }
     };
 
-    thread::spawn(move || {
-      let underlying_socket = match underlying_stream.peer_addr() {
-        Ok(socket) => socket,
-        Err(_) => {
-          println!("Get underlying socket failed");
-          return;
-        }
-      };
+    let underlying_socket = match underlying_stream.peer_addr() {
+      Ok(socket) => socket,
+      Err(_) => {
+        eprintln!("Get underlying socket failed");
+        return;
+      }
+    };
 
-      println!("\nProxy: {} <-> master <-> {}", target_socket, underlying_socket);
-      let mut underlying_buf_stream = BufStream::new(underlying_stream);
-      for header in &headers {
-        if let Err(e) = underlying_buf_stream.write_all(header.as_bytes()) {
-          println!("Write to underlying stream failed: {}", e);
-          return;
-        }
+    eprintln!("\nProxy: {} <-> master <-> {}", target_socket, underlying_socket);
+    let mut underlying_buf_stream = BufStream::new(underlying_stream);
+    for header in &headers {
+      if let Err(e) = underlying_buf_stream.write_all(header.as_bytes()) {
+        eprintln!("Write to underlying stream failed: {}", e);
+        return;
       }
-      if let Err(e) = underlying_buf_stream
-        .write_all(b"\r\n")
-        .and_then(|_| underlying_buf_stream.flush())
-      {
-        println!("Write to underlying stream failed: {}", e);
+    }
+    if let Err(e) = underlying_buf_stream
+      .write_all(b"\r\n")
+      .and_then(|_| underlying_buf_stream.flush())
+    {
+      eprintln!("Write to underlying stream failed: {}", e);
+      return;
+    }
+    headers.clear();
+    loop {
+      let mut line = String::new();
+      if let Err(e) = underlying_buf_stream.read_line(&mut line) {
+        eprintln!("Read data from underlying stream failed: {}", e);
         return;
       }
-      headers.clear();
-      loop {
-        let mut line = String::new();
-        if let Err(e) = underlying_buf_stream.read_line(&mut line) {
-          println!("Read data from underlying stream failed: {}", e);
-          return;
-        }
-        if line == "\r\n" {
-          break;
-        }
-        if content_length.is_none() && request_method == "GET" && line.starts_with("Content-Length:") {
-          content_length = match line.trim().split(':').take(2).last() {
-            Some(value) => match value.trim().parse::<usize>() {
-              Ok(v) => Some(v),
-              Err(e) => {
-                println!("Parse content length from header failed: {}", e);
-                return;
-              }
-            },
-            None => {
-              println!("Parse content length from header failed");
-              return;
-            }
-          }
-        }
-        headers.push(line);
-        if headers.len() > 100 {
-          println!("Too many headers from {}", underlying_socket);
-          return;
-        }
+      if line == "\r\n" {
+        break;
       }
-      let content_length = content_length.unwrap();
-      for header in &headers {
-        if let Err(e) = buf_stream.write_all(header.as_bytes()) {
-          println!("Write to target stream failed: {}", e);
-          return;
-        }
+      headers.push(line);
+      if headers.len() > 100 {
+        eprintln!("Too many headers from {}", underlying_socket);
+        return;
+      }
+    }
+    // Uploads relay the request body, the response of an upload is relayed after it until the receiver closes
+    let body = match Body::from_headers(&headers) {
+      _ if is_upload => request_body,
+      _ if request_method == "HEAD" => Body::Length(0),
+      Ok(Some(body)) => body,
+      Ok(None) => Body::UntilClose,
+      Err(e) => {
+        eprintln!("{} from {}", e, underlying_socket);
+        return;
       }
-      if let Err(e) = buf_stream.write_all(b"\r\n").and_then(|_| buf_stream.flush()) {
-        println!("Write to target stream failed: {}", e);
+    };
+    for header in &headers {
+      if let Err(e) = buf_stream.write_all(header.as_bytes()) {
+        eprintln!("Write to target stream failed: {}", e);
         return;
       }
+    }
+    if let Err(e) = buf_stream.write_all(b"\r\n").and_then(|_| buf_stream.flush()) {
+      eprintln!("Write to target stream failed: {}", e);
+      return;
+    }
 
-      let (reader, writer) = if request_method == "GET" {
-        (&mut underlying_buf_stream, &mut buf_stream)
-      } else {
-        (&mut buf_stream, &mut underlying_buf_stream)
-      };
-      let mut left_size = content_length;
-      let mut buf = [0u8; 16 * 1024];
-      let mut send_size = 0;
-      while left_size > 0 {
-        match reader.read(&mut buf) {
-          Ok(n) => {
-            if let Err(e) = writer.write_all(&buf[..n]) {
-              println!("Write to stream failed: {}", e);
-              return;
-            }
-            left_size -= n;
-            send_size += n;
-            if send_size >= 16 * 1024 * 1024 {
-              if let Err(e) = writer.flush() {
-                println!("Flush writer failed: {}", e);
-                return;
-              }
-              send_size = 0;
-            }
-          }
-          Err(_) => {
-            println!("Read from stream failed");
-            return;
-          }
-        }
-      }
-      if let Err(e) = writer.flush() {
-        println!("Flush writer failed: {}", e);
+    let (reader, writer): (&mut dyn BufRead, &mut dyn Write) = if !is_upload {
+      (&mut underlying_buf_stream, &mut buf_stream)
+    } else {
+      (&mut buf_stream, &mut underlying_buf_stream)
+    };
+    let result = match body {
+      Body::Length(length) => io::copy(&mut reader.take(length), writer),
+      Body::Chunked => http::copy_chunked(reader, writer),
+      Body::UntilClose => io::copy(reader, writer),
+    };
+    if let Err(e) = result.and_then(|_| writer.flush()) {
+      eprintln!("Relay body failed: {}", e);
+      return;
+    }
+    if is_upload {
+      let mut buf = vec![];
+      // What has been read of the response is relayed even if the rest is lost
+      let result = underlying_buf_stream.read_to_end(&mut buf);
+      if let Err(e) = buf_stream.write_all(&buf).and_then(|_| buf_stream.flush()) {
+        eprintln!("Write to target stream failed: {}", e);
         return;
       }
-      if request_method == "POST" {
-        let mut buf = vec![];
-        if let Err(e) = underlying_buf_stream.read_to_end(&mut buf) {
-          println!("Read from underlying stream failed: {}", e);
-          return;
-        }
-        if let Err(e) = buf_stream.write_all(&buf).and_then(|_| buf_stream.flush()) {
-          println!("Write to target stream failed: {}", e);
-          return;
-        }
+      if let Err(e) = result {
+        eprintln!("Read from underlying stream failed: {}", e);
+        return;
       }
-      println!("Proxy: {} <-> master <-> {} done", target_socket, underlying_socket);
-    });
+    }
+    eprintln!("Proxy: {} <-> master <-> {} done", target_socket, underlying_socket);
   }
 
-  fn get_transport_stream(&self, key: &str) -> Option<TcpStream> {
-    match self.master_event.sender.send(key.to_string()) {
-      Ok(_) => match self.master_event.receiver.recv() {
-        Ok((recv_key, stream)) => {
-          if recv_key == key {
-            Some(stream)
-          } else {
-            None
-          }
-        }
+  /// Ask the consumer of `key` to connect back, the stream is paired with this request by a correlation id
+  fn get_transport_stream(&self, key: &str) -> Option<Transport> {
+    // A multiplexed consumer needs no connecting back
+    let mux = self
+      .registry
+      .masters
+      .lock()
+      .unwrap()
+      .get(key)
+      .and_then(|master| master.mux.clone());
+    if let Some(mux) = mux {
+      return match mux.open() {
+        Ok(stream) => Some(Transport::Mux(stream)),
         Err(e) => {
-          println!("Recv from master failed: {}", e);
+          eprintln!("Open a stream to the master of {} failed: {}", key, e);
           None
         }
-      },
-      Err(e) => {
-        println!("Send to master failed: {}", e);
-        None
-      }
+      };
     }
+    let (id, receiver) = self.registry.add_pending(key);
+    let stream = if self.registry.notify(key, None, &format!("REQUEST {}", id)) {
+      match receiver.recv_timeout(TRANSPORT_TIMEOUT) {
+        Ok(stream) => Some(Transport::Tcp(stream)),
+        Err(_) => {
+          eprintln!("Wait for the stream of {} timed out", key);
+          None
+        }
+      }
+    } else {
+      eprintln!("Unknown key: {}", key);
+      None
+    };
+    self.registry.pending.lock().unwrap().remove(&id);
+    stream
   }
 }
 
 pub struct ProxyConsumer {
   pub public_socket: SocketAddr,
+  /// The key published by the proxy, prefixed by the token name when the proxy requires tokens
+  pub key: String,
   pub master_stream: TcpStream,
+  /// TLS of the public listener, if enabled on the proxy
+  pub tls: Option<TlsInfo>,
+  pub token: Option<ProxyToken>,
+  /// The control address registered at, known even if the connection has dropped since
+  master_socket: SocketAddr,
+  /// The key as asked for, to register again
+  request_key: String,
+  /// The ping interval of the proxy master, `None` if it sends no heartbeats
+  heartbeat: Option<Duration>,
+  /// Transports are multiplexed over the master stream
+  mux: bool,
 }
 
 impl ProxyConsumer {
-  fn try_get_one(ip: IpAddr, key: &str) -> Option<Self> {
-    let addrs = [SocketAddr::from((ip, 7070)), SocketAddr::from((ip, 7575))];
-    for addr in &addrs {
-      match TcpStream::connect_timeout(addr, Duration::from_millis(200)) {
-        Ok(stream) => {
-          if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(500))) {
-            println!("Set read timeout for stream failed: {}", e);
-            continue;
-          }
-          let mut buf_stream = BufStream::new(stream);
-          if let Err(e) = buf_stream
-            .write_all(format!("PROXY {}\r\n\r\n", key).as_bytes())
-            .and_then(|_| buf_stream.flush())
-          {
-            println!("Writer to proxy failed: {}", e);
-            continue;
-          }
-          let mut request = String::new();
-          if let Err(e) = buf_stream.read_line(&mut request) {
-            println!("Read data from proxy master failed: {}", e);
-            continue;
-          }
-          let mut line = String::new();
-          match buf_stream.read_line(&mut line) {
-            Ok(_) => {
-              if line != "\r\n" {
-                println!("Bad request from proxy master");
-                continue;
-              }
-            }
-            Err(e) => {
-              println!("Read data from proxy master failed: {}", e);
-              continue;
-            }
-          }
-
-          match SocketAddr::from_str(request.trim()) {
-            Ok(socket) => {
-              let stream = buf_stream.into_inner().unwrap();
-              if let Err(e) = stream.set_read_timeout(None) {
-                println!("Set read timeout for stream failed: {}", e);
-                continue;
-              }
-              return Some(Self {
-                public_socket: socket,
-                master_stream: stream,
-              });
-            }
-            Err(_) => {
-              continue;
-            }
-          }
+  fn try_get_one(addrs: &[SocketAddr], key: &str, token: Option<&ProxyToken>) -> Option<Self> {
+    for addr in addrs {
+      // Proxy masters too old to multiplex drop the connection, they are asked again to connect back instead
+      for mux in [true, false] {
+        match Self::register(addr, key, token, mux) {
+          Ok(consumer) => return Some(consumer),
+          Err(retry) if retry && mux => continue,
+          Err(_) => break,
         }
-        Err(_) => continue,
       }
     }
     None
   }
 
-  pub fn try_get(proxy_servers: &[IpAddr], key: &str) -> Option<Self> {
-    for ip in proxy_servers {
-      if let Some(proxy) = Self::try_get_one(*ip, key) {
+  /// Register `key` at `addr`, on failure whether it is worth to try again without multiplexing is returned
+  fn register(addr: &SocketAddr, key: &str, token: Option<&ProxyToken>, mux: bool) -> Result<Self, bool> {
+    let stream = TcpStream::connect_timeout(addr, Duration::from_millis(200)).map_err(|_| false)?;
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(500))) {
+      eprintln!("Set read timeout for stream failed: {}", e);
+      return Err(false);
+    }
+    let mut buf_stream = BufStream::new(stream);
+    let command = format!("{} {}", if mux { "MUX" } else { "PROXY" }, key);
+    if let Err(e) = write_command(&mut buf_stream, &command, token) {
+      eprintln!("Writer to proxy failed: {}", e);
+      return Err(false);
+    }
+    // Nothing after the nonce is buffered yet, the reply is read byte by byte as frames may follow it
+    let stream = buf_stream.into_inner().map_err(|_| false)?;
+    let request = match read_message(&mut BufReader::with_capacity(1, &stream)) {
+      Ok(request) => request,
+      Err(e) => {
+        if !mux {
+          eprintln!("Read data from proxy master failed: {}", e);
+        }
+        return Err(true);
+      }
+    };
+    if let Some(e) = request.strip_prefix("ERROR ") {
+      eprintln!("Proxy {} refused: {}", addr, e);
+      return Err(false);
+    }
+
+    let mut chunks = request.split_whitespace();
+    let Some(Ok(socket)) = chunks.next().map(SocketAddr::from_str) else {
+      return Err(false);
+    };
+    let (mut public_key, mut fingerprint, mut pinned_pubkey) = (key.to_string(), None, None);
+    let mut heartbeat = None;
+    for chunk in chunks {
+      match chunk.split_once('=') {
+        Some(("key", value)) => public_key = value.to_string(),
+        Some(("heartbeat", value)) => heartbeat = value.parse().ok().map(Duration::from_secs),
+        Some(("fingerprint", value)) => fingerprint = Some(value.to_string()),
+        Some(("pin", value)) => pinned_pubkey = Some(value.to_string()),
+        _ => {}
+      }
+    }
+    if let Err(e) = stream.set_read_timeout(None) {
+      eprintln!("Set read timeout for stream failed: {}", e);
+      return Err(false);
+    }
+    Ok(Self {
+      public_socket: socket,
+      key: public_key,
+      master_stream: stream,
+      tls: fingerprint.map(|fingerprint| TlsInfo {
+        fingerprint,
+        pinned_pubkey,
+      }),
+      token: token.cloned(),
+      master_socket: *addr,
+      request_key: key.to_string(),
+      heartbeat,
+      mux,
+    })
+  }
+
+  pub fn try_get(proxy_servers: &[ProxyAddr], key: &str, token: Option<&ProxyToken>) -> Option<Self> {
+    for proxy_server in proxy_servers {
+      if let Some(proxy) = Self::try_get_one(&proxy_server.socket_addrs(&CONTROL_PORTS), key, token) {
         return Some(proxy);
       }
     }
 
-    let addrs = [
-      SocketAddr::from(([127, 0, 0, 1], 7070)),
-      SocketAddr::from(([127, 0, 0, 1], 7575)),
-    ];
-    for addr in &addrs {
-      match TcpStream::connect_timeout(addr, Duration::from_millis(100)) {
+    for addr in CONTROL_PORTS.map(|port| SocketAddr::from(([127, 0, 0, 1], port))) {
+      match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
         Ok(stream) => {
           if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(100))) {
-            println!("Set read timeout of stream failed: {}", e);
+            eprintln!("Set read timeout of stream failed: {}", e);
             continue;
           }
           let mut buf_stream = BufStream::new(stream);
           if let Err(e) = buf_stream.write_all(b"PING\r\n\r\n").and_then(|_| buf_stream.flush()) {
-            println!("Write to proxy master failed: {}", e);
+            eprintln!("Write to proxy master failed: {}", e);
             continue;
           }
           let mut request = String::new();
           if let Err(e) = buf_stream.read_line(&mut request) {
-            println!("Read data from proxy master failed: {}", e);
+            eprintln!("Read data from proxy master failed: {}", e);
             continue;
           }
           let mut line = String::new();
           match buf_stream.read_line(&mut line) {
             Ok(_) => {
               if line != "\r\n" {
-                println!("Bad request from proxy master");
+                eprintln!("Bad request from proxy master");
                 continue;
               }
             }
             Err(e) => {
-              println!("Read data from proxy master failed: {}", e);
+              eprintln!("Read data from proxy master failed: {}", e);
               continue;
             }
           }
@@ -605,7 +1114,8 @@ impl ProxyConsumer {
       .collect::<Vec<_>>();
     for interface in interfaces {
       let gateway = interface.gateway.as_ref().unwrap().ip_addr;
-      if let Some(proxy) = Self::try_get_one(gateway, key) {
+      let addrs = CONTROL_PORTS.map(|port| SocketAddr::from((gateway, port)));
+      if let Some(proxy) = Self::try_get_one(&addrs, key, token) {
         return Some(proxy);
       }
     }
@@ -0,0 +1,70 @@
+use std::{
+  cell::RefCell,
+  sync::Mutex,
+  thread,
+  time::{Duration, Instant},
+};
+
+/// Token bucket refilled at `rate` bytes per second, bursts of up to a second worth of bytes pass without waiting
+struct Bucket {
+  rate: f64,
+  tokens: f64,
+  last: Instant,
+}
+
+impl Bucket {
+  fn new(rate: u64) -> Self {
+    Self {
+      rate: rate as f64,
+      tokens: rate as f64,
+      last: Instant::now(),
+    }
+  }
+
+  /// Take `n` tokens even if there are not enough, the time to wait until the debt is paid off is returned
+  fn take(&mut self, n: usize) -> Duration {
+    let now = Instant::now();
+    self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+    self.last = now;
+    self.tokens -= n as f64;
+    if self.tokens < 0.0 {
+      Duration::from_secs_f64(-self.tokens / self.rate)
+    } else {
+      Duration::ZERO
+    }
+  }
+}
+
+static GLOBAL: Mutex<Option<Bucket>> = Mutex::new(None);
+static PER_CONN_RATE: Mutex<Option<u64>> = Mutex::new(None);
+
+thread_local! {
+  // Every connection is served by a thread of its own
+  static CONN: RefCell<Option<Bucket>> = const { RefCell::new(None) };
+}
+
+/// Limit all connections together to `global` and each one to `per_conn` bytes per second
+pub fn limit(global: Option<u64>, per_conn: Option<u64>) {
+  *GLOBAL.lock().unwrap() = global.map(Bucket::new);
+  *PER_CONN_RATE.lock().unwrap() = per_conn;
+}
+
+/// Account `n` bytes transferred by the connection of the current thread, sleeping while a limit is exceeded
+pub fn throttle(n: usize) {
+  if n == 0 {
+    return;
+  }
+  let global_wait = GLOBAL
+    .lock()
+    .unwrap()
+    .as_mut()
+    .map_or(Duration::ZERO, |bucket| bucket.take(n));
+  let conn_wait = match *PER_CONN_RATE.lock().unwrap() {
+    Some(rate) => CONN.with_borrow_mut(|bucket| bucket.get_or_insert_with(|| Bucket::new(rate)).take(n)),
+    None => Duration::ZERO,
+  };
+  let wait = global_wait.max(conn_wait);
+  if !wait.is_zero() {
+    thread::sleep(wait);
+  }
+}
@@ -1,62 +1,174 @@
 use std::{
   env,
-  fs::{self, File},
-  io::{BufRead, BufWriter, Read, Write},
-  net::{SocketAddr, TcpListener, TcpStream},
-  path::PathBuf,
+  ffi::OsString,
+  fs::{self, File, OpenOptions},
+  io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write},
+  net::SocketAddr,
+  path::{Path, PathBuf},
   process,
-  str::FromStr,
+  sync::{Arc, Mutex},
   thread,
+  time::Duration,
 };
 
 use base64::{Engine as _, engine::general_purpose};
 use bufstream::BufStream;
+use flate2::read::MultiGzDecoder;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use serde_derive::Serialize;
+use tar::{Archive, Entry};
 
-use crate::proxy::{ProxyConsumer, ProxyMaster};
+use crate::{
+  digest::{self, Checksum, Digest, HashWriter, Hasher},
+  drain,
+  http::{self, BodyReader, ChunkedReader, MultipartReader},
+  iface,
+  proxy::{ProxyConsumer, ProxyMaster},
+  secure,
+  stream::Stream,
+  tls::Tls,
+};
 
 static UPLOAD_HTML: &[u8] = include_bytes!("html/upload.html");
+static TO_STDOUT: Mutex<bool> = Mutex::new(false);
+/// Whether an upload is being written to stdout, only a single one is accepted
+static STDOUT_TAKEN: Mutex<bool> = Mutex::new(false);
+/// Received files so far and the limit of them, read together under one lock
+static UPLOADS: Mutex<(u64, Option<u64>)> = Mutex::new((0, None));
+static EXPIRE: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// The outcome of one file of a multipart upload
+#[derive(Serialize)]
+struct PartResult {
+  name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  path: Option<String>,
+  size: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  sha256: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
 
 pub struct Recv {}
 
 impl Recv {
-  pub fn run(key: &str, socket: SocketAddr, reserve: bool, proxy: Option<ProxyConsumer>, auto_rename: bool) {
-    Self::recv(key, socket, reserve, proxy, auto_rename);
+  pub fn run(
+    key: &str, socket: SocketAddr, reserve: bool, proxy: Option<ProxyConsumer>, auto_rename: bool, output_dir: PathBuf,
+    tls: Option<Arc<Tls>>,
+  ) {
+    Self::recv(key, socket, reserve, proxy, auto_rename, output_dir, tls);
   }
 
-  fn to_os_path(path: &str, reserve: bool) -> PathBuf {
-    let path = path.trim();
-    if env::consts::OS == "windows" {
-      let p = path.replace('/', "\\");
-      if reserve && !p.starts_with('\\') {
-        PathBuf::from_str(&p).unwrap()
-      } else {
-        PathBuf::from_str(p.split('\\').last().unwrap()).unwrap()
+  /// Write a single upload to stdout instead of the output directory
+  pub fn write_to_stdout() {
+    *TO_STDOUT.lock().unwrap() = true;
+  }
+
+  /// Stop serving after `max_uploads` received files, or once `expire` has passed
+  pub fn stop_after(max_uploads: Option<u64>, expire: Option<Duration>) {
+    UPLOADS.lock().unwrap().1 = max_uploads;
+    *EXPIRE.lock().unwrap() = expire;
+  }
+
+  fn count_uploads(files: u64) {
+    if files == 0 {
+      return;
+    }
+    let (uploads, max_uploads) = {
+      let mut uploads = UPLOADS.lock().unwrap();
+      uploads.0 += files;
+      *uploads
+    };
+    // Only the upload reaching the limit stops the receiver, those in flight are waited for
+    if let Some(max_uploads) = max_uploads
+      && uploads >= max_uploads
+      && uploads - files < max_uploads
+    {
+      eprintln!("\nReached the limit of {} uploads, stopping", max_uploads);
+      drain::stop();
+      Self::shutdown(0);
+    }
+  }
+
+  /// Map the `File-Path` header to a path beneath `root`, rejecting anything that may escape from it
+  pub fn to_local_path(path: &str, reserve: bool, root: &Path) -> Result<PathBuf, String> {
+    let path = path.trim().replace('\\', "/");
+    if path.chars().any(|c| c.is_control()) {
+      return Err("control character in path".to_string());
+    }
+    let mut components = path
+      .split('/')
+      .filter(|c| !c.is_empty() && *c != ".")
+      .collect::<Vec<_>>();
+    if reserve {
+      if path.starts_with('/') || path.split('/').next().is_some_and(|c| c.contains(':')) {
+        return Err("absolute path is not allowed".to_string());
       }
     } else {
-      let p = path.replace('\\', "/");
-      if reserve && !p.contains(':') {
-        PathBuf::from_str(&p).unwrap()
-      } else {
-        PathBuf::from_str(p.split('/').last().unwrap()).unwrap()
+      components = components.split_off(components.len().saturating_sub(1));
+    }
+    if components.is_empty() {
+      return Err("empty path".to_string());
+    }
+    for component in &components {
+      if *component == ".." {
+        return Err("parent directory is not allowed".to_string());
+      }
+      if env::consts::OS == "windows" && (component.contains(':') || Self::is_device_name(component)) {
+        return Err(format!("invalid file name {:?}", component));
+      }
+    }
+    let local_path = components.iter().fold(root.to_path_buf(), |path, c| path.join(c));
+    Self::check_confined(&local_path, root)?;
+    Ok(local_path)
+  }
+
+  fn is_device_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end().to_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$")
+      || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+        && stem.len() == 4
+        && stem.as_bytes()[3].is_ascii_digit()
+        && stem.as_bytes()[3] != b'0')
+  }
+
+  /// Make sure the deepest existing ancestor of `local_path` doesn't resolve (e.g. through symlinks) outside `root`
+  fn check_confined(local_path: &Path, root: &Path) -> Result<(), String> {
+    let root = if root.as_os_str().is_empty() {
+      Path::new(".")
+    } else {
+      root
+    };
+    let root = fs::canonicalize(root).map_err(|e| format!("resolve output directory failed: {}", e))?;
+    let mut ancestor = local_path;
+    while fs::symlink_metadata(ancestor).is_err() {
+      match ancestor.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => ancestor = parent,
+        _ => return Ok(()),
       }
     }
+    match fs::canonicalize(ancestor) {
+      Ok(resolved) if resolved.starts_with(&root) => Ok(()),
+      Ok(resolved) => Err(format!("{:?} resolves to {:?} outside {:?}", ancestor, resolved, root)),
+      Err(e) => Err(format!("resolve {:?} failed: {}", ancestor, e)),
+    }
   }
 
-  fn handle_recv(stream: TcpStream, key: &str, reserve: bool, auto_rename: bool) {
+  fn handle_recv(stream: Stream, key: &str, reserve: bool, auto_rename: bool, output_dir: &Path) {
     let peer_addr = match stream.peer_addr() {
       Ok(peer_addr) => peer_addr,
       Err(e) => {
-        println!("Get peer socket failed: {}", e);
+        eprintln!("Get peer socket failed: {}", e);
         return;
       }
     };
-    let mut buf_stream = BufStream::new(&stream);
+    let mut buf_stream = BufStream::new(stream);
     let mut headers = vec![];
     loop {
       let mut line = String::new();
       if let Err(e) = buf_stream.read_line(&mut line) {
-        println!("Read line from buffer failed: {}", e);
+        eprintln!("Read line from buffer failed: {}", e);
         return;
       }
       if line == "\r\n" {
@@ -64,15 +176,27 @@ impl Recv {
       }
       headers.push(line);
       if headers.len() > 100 {
-        println!("Too many headers from {}", peer_addr);
+        eprintln!("Too many headers from {}", peer_addr);
         return;
       }
     }
     if headers.is_empty() {
-      println!("Empty request headers from {}", peer_addr);
+      eprintln!("Empty request headers from {}", peer_addr);
       return;
     }
 
+    if secure::is_enabled() {
+      Self::handle_secure_recv(
+        &mut buf_stream,
+        &headers,
+        key,
+        peer_addr,
+        reserve,
+        auto_rename,
+        output_dir,
+      );
+      return;
+    }
     if headers[0].trim().starts_with(&format!("GET /{} HTTP/", key)) {
       if let Err(e) = buf_stream
         .write_all(
@@ -87,207 +211,873 @@ impl Recv {
         )
         .and_then(|_| buf_stream.write_all(UPLOAD_HTML).and_then(|_| buf_stream.flush()))
       {
-        println!("Write response failed: {}", e);
+        eprintln!("Write response failed: {}", e);
       }
       return;
     }
-    if !headers[0].trim().starts_with(&format!("POST /{} HTTP/", key)) {
-      println!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+    let is_head = headers[0].trim().starts_with(&format!("HEAD /{} HTTP/", key));
+    if !is_head
+      && !headers[0].trim().starts_with(&format!("POST /{} HTTP/", key))
+      && !headers[0].trim().starts_with(&format!("PUT /{} HTTP/", key))
+    {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
       if let Err(e) = buf_stream
         .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
         .and_then(|_| buf_stream.flush())
       {
-        println!("Write response header failed: {}", e);
+        eprintln!("Write response header failed: {}", e);
       }
       return;
     }
 
-    if let Err(e) = buf_stream
-      .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
-      .and_then(|_| buf_stream.flush())
-    {
-      println!("Write response header failed: {}", e);
+    if *TO_STDOUT.lock().unwrap() {
+      Self::handle_stdout(&mut buf_stream, &headers, is_head, peer_addr);
+      return;
+    }
+    if !is_head && let Some(boundary) = http::header(&headers, "Content-Type").and_then(http::multipart_boundary) {
+      let saved = Self::handle_multipart(
+        &mut buf_stream,
+        &headers,
+        boundary,
+        peer_addr,
+        reserve,
+        auto_rename,
+        output_dir,
+      );
+      Self::count_uploads(saved);
       return;
     }
 
-    let mut content_length = None;
-    let mut file_path = None;
-    for header in headers.into_iter().skip(1) {
-      if content_length.is_none() && header.starts_with("Content-Length:") {
-        content_length = match header.trim().split(':').take(2).last() {
-          Some(value) => match value.trim().parse::<usize>() {
-            Ok(v) => Some(v),
-            Err(e) => {
-              println!("Parse content length from header failed: {}", e);
-              return;
-            }
-          },
-          None => {
-            println!("Parse content length from header failed");
-            return;
-          }
+    let file_path = match http::header(&headers, "File-Path") {
+      Some(path) => match Self::to_local_path(path, reserve, output_dir) {
+        Ok(file_path) => file_path,
+        Err(e) => {
+          eprintln!("Rejected file path {:?} from {}: {}", path, peer_addr, e);
+          Self::respond(&mut buf_stream, "403 Forbidden", "");
+          return;
         }
+      },
+      None => {
+        eprintln!("Get file path failed, Fallback to \"{}\"", key);
+        output_dir.join(key)
       }
-      if file_path.is_none() && header.starts_with("File-Path:") {
-        file_path = match header.split(':').take(2).last() {
-          Some(path) => Some(Self::to_os_path(path, reserve)),
-          None => {
-            println!("Get file path failed, Fallback to \"{}\"", key);
-            Some(PathBuf::from_str(&key).unwrap())
-          }
-        };
-        if let Some(p) = file_path.as_ref().unwrap().parent() {
-          if !p.is_dir() {
-            if let Err(e) = fs::create_dir_all(p) {
-              println!("Create directory failed: {}", e);
-              return;
-            }
-          }
-        }
+    };
+    let part_path = Self::get_part_path(&file_path);
+    let offset = fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+    if is_head {
+      if let Err(e) = buf_stream
+        .write_all(format!("HTTP/1.1 200 OK\r\nUpload-Offset: {offset}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+        .and_then(|_| buf_stream.flush())
+      {
+        eprintln!("Write response header failed: {}", e);
       }
+      return;
     }
-    let content_length = content_length.unwrap();
-    let mut file_path = file_path.unwrap();
-    if auto_rename && file_path.is_file() {
-      let mut new_path = file_path.clone();
-      let mut i = 1;
-      while new_path.is_file() {
-        if file_path.extension().is_none() {
-          new_path = file_path.with_file_name(format!("{}-{}", file_path.file_stem().unwrap().to_str().unwrap(), i));
-        } else {
-          new_path = file_path.with_file_name(format!(
-            "{}-{}.{}",
-            file_path.file_stem().unwrap().to_str().unwrap(),
-            i,
-            file_path.extension().unwrap().to_str().unwrap(),
-          ));
+
+    let is_chunked = http::is_chunked(&headers);
+    let Some(content_length) = Self::content_length(&mut buf_stream, &headers, peer_addr) else {
+      return;
+    };
+    let (start, total) = match http::header(&headers, "Content-Range") {
+      Some(value) => match http::parse_content_range(value) {
+        Some((start, end, total)) if Some(end - start + 1) == content_length => (start, Some(total)),
+        _ => {
+          eprintln!("Bad content range from {}: {}", peer_addr, value);
+          Self::respond(&mut buf_stream, "400 Bad Request", "");
+          return;
         }
-        i += 1;
+      },
+      None => (0, content_length),
+    };
+    let mut checksums = match Checksum::from_headers(&headers) {
+      Ok(checksums) => checksums,
+      Err(e) => {
+        eprintln!("Bad checksum from {}: {}", peer_addr, e);
+        Self::respond(&mut buf_stream, "400 Bad Request", "");
+        return;
+      }
+    };
+    let encoding = http::header(&headers, "Content-Encoding")
+      .map(|value| value.to_lowercase())
+      .filter(|value| value != "identity");
+    if let Some(encoding) = &encoding {
+      if !matches!(encoding.as_str(), "gzip" | "x-gzip" | "zstd") {
+        eprintln!("Unsupported content encoding from {}: {}", peer_addr, encoding);
+        Self::respond(
+          &mut buf_stream,
+          "415 Unsupported Media Type",
+          "Accept-Encoding: gzip, zstd\r\n",
+        );
+        return;
+      }
+      if total != content_length {
+        eprintln!("Encoded upload from {} cannot be partial", peer_addr);
+        Self::respond(&mut buf_stream, "400 Bad Request", "");
+        return;
       }
-      println!(
-        "\nRecving {:?} from {} (local path: {:?})",
-        &file_path, peer_addr, &new_path
+    }
+    if start > offset {
+      eprintln!(
+        "Cannot resume {:?} from byte {}, only {} bytes received",
+        file_path, start, offset
+      );
+      Self::respond(
+        &mut buf_stream,
+        "416 Range Not Satisfiable",
+        &format!("Upload-Offset: {offset}\r\n"),
       );
-      file_path = new_path;
+      return;
+    }
+    if !Self::send_continue(&mut buf_stream) {
+      return;
+    }
+
+    if start > 0 {
+      eprintln!("\nResuming {:?} from {} at byte {}", &file_path, peer_addr, start);
     } else {
-      println!("\nRecving {:?} from {}", &file_path, peer_addr);
+      eprintln!("\nRecving {:?} from {}", &file_path, peer_addr);
     }
 
-    let pb = ProgressBar::new(content_length as u64);
-    pb.set_style(
-      ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| {
-          write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-        })
-        .progress_chars("#>-"),
-    );
+    let pb = match total {
+      Some(total) => Self::progress_bar(total),
+      None => Self::spinner(),
+    };
+    pb.set_position(start);
 
-    let mut buf = [0u8; 16 * 1024];
-    if let Some(folder) = file_path.parent() {
-      if !folder.exists() {
-        if let Err(e) = fs::create_dir_all(folder) {
-          println!("Failed to create folder: {}", e);
-          return;
-        }
-      }
+    if let Some(folder) = file_path.parent()
+      && !folder.exists()
+      && let Err(e) = fs::create_dir_all(folder)
+    {
+      eprintln!("Failed to create folder: {}", e);
+      return;
     }
-    let mut file_writer = match File::create(&file_path) {
+    let mut hasher = Hasher::for_checksums(&checksums);
+    // Checksums of chunked uploads may also come as trailers
+    if is_chunked && http::header(&headers, "Trailer").is_some() {
+      hasher = hasher.with_md5();
+    }
+    if start > 0
+      && let Err(e) = File::open(&part_path).and_then(|file| io::copy(&mut file.take(start), &mut hasher))
+    {
+      eprintln!("Read partial received file failed: {}", e);
+      return;
+    }
+    let mut file_writer = match OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&part_path)
+      .and_then(|mut file| file.set_len(start).and(file.seek(SeekFrom::End(0))).map(|_| file))
+    {
       Ok(file) => BufWriter::new(file),
       Err(e) => {
-        println!("Create output file failed: {}", e);
+        eprintln!("Create output file failed: {}", e);
         return;
       }
     };
-    let mut left_size = content_length;
-    let mut send_size = 0;
-    while left_size > 0 {
-      match buf_stream.read(&mut buf) {
-        Ok(n) => {
-          if let Err(e) = file_writer.write_all(&buf[..n]) {
-            println!("Write to output file failed: {}", e);
-            return;
-          }
-          pb.inc(n as u64);
-          left_size -= n;
-          send_size += n;
-          if send_size >= 16 * 1024 * 1024 {
-            if let Err(e) = buf_stream.flush() {
-              println!("Flush writer failed: {}", e);
+    let mut body = match content_length {
+      Some(content_length) => BodyReader::Sized((&mut buf_stream).take(content_length)),
+      None => BodyReader::Chunked(ChunkedReader::new(&mut buf_stream)),
+    };
+    let mut received = start;
+    if let Some(encoding) = &encoding {
+      if let Err(e) = Self::decode_into(pb.wrap_read(&mut body), Some(encoding), &mut file_writer, &mut hasher) {
+        eprintln!(
+          "Decode {} upload of {:?} from {} failed: {}",
+          encoding, file_path, peer_addr, e
+        );
+        drop(file_writer);
+        if let Err(e) = fs::remove_file(&part_path) {
+          eprintln!("Remove partial file {:?} failed: {}", part_path, e);
+        }
+        Self::respond(&mut buf_stream, "400 Bad Request", "");
+        return;
+      }
+      received = total.unwrap_or_default();
+    } else {
+      let mut buf = [0u8; 16 * 1024];
+      loop {
+        match body.read(&mut buf) {
+          Ok(0) => break,
+          Ok(n) => {
+            if let Err(e) = file_writer.write_all(&buf[..n]) {
+              eprintln!("Write to output file failed: {}", e);
               return;
             }
-            send_size = 0;
+            hasher.update(&buf[..n]);
+            pb.inc(n as u64);
+            received += n as u64;
+          }
+          Err(e) => {
+            eprintln!("Read data from stream failed: {}", e);
+            if let Err(e) = file_writer.flush() {
+              eprintln!("Flush write buffer failed: {}", e);
+            }
+            eprintln!("Partial received file has been kept as {:?} for resuming", part_path);
+            return;
           }
         }
-        Err(e) => {
-          println!("Read data from stream failed: {}", e);
-          fs::remove_file(&file_path).expect("Remove partial received file failed");
-          println!("Partial received file has been deleted");
-          return;
+      }
+      if let Some(content_length) = content_length
+        && received < start + content_length
+      {
+        eprintln!("Connection from {} closed before upload completed", peer_addr);
+        if let Err(e) = file_writer.flush() {
+          eprintln!("Flush write buffer failed: {}", e);
         }
+        eprintln!("Partial received file has been kept as {:?} for resuming", part_path);
+        return;
+      }
+    }
+    let trailer_checksums = Checksum::from_headers(body.trailers());
+    drop(body);
+    match trailer_checksums {
+      Ok(trailer_checksums) => checksums.extend(trailer_checksums),
+      Err(e) => {
+        eprintln!("Bad checksum trailer from {}: {}", peer_addr, e);
+        Self::respond(&mut buf_stream, "400 Bad Request", "");
+        return;
       }
     }
-    if let Err(e) = file_writer.flush() {
-      println!("Flush write buffer failed: {}", e);
+    if let Err(e) = file_writer
+      .into_inner()
+      .map_err(|e| e.into_error())
+      .and_then(|file| file.sync_all())
+    {
+      eprintln!("Flush output file failed: {}", e);
+      return;
+    }
+
+    if let Some(total) = total
+      && received < total
+    {
+      Self::respond(&mut buf_stream, "200 OK", &format!("Upload-Offset: {received}\r\n"));
+      eprintln!(
+        "Recv {}/{} bytes of {:?} from {}",
+        received, total, file_path, peer_addr
+      );
+      return;
+    }
+
+    let digest = hasher.finalize();
+    if let Err(e) = digest.verify(&checksums) {
+      eprintln!("Verify {:?} from {} failed: {}", file_path, peer_addr, e);
+      if let Err(e) = fs::remove_file(&part_path) {
+        eprintln!("Remove corrupted file {:?} failed: {}", part_path, e);
+      }
+      Self::respond(&mut buf_stream, "422 Unprocessable Entity", "");
       return;
     }
+    let local_path = match Self::commit_part(&part_path, &file_path, auto_rename) {
+      Ok(local_path) => local_path,
+      Err(e) => {
+        eprintln!("Move {:?} to {:?} failed: {}", part_path, file_path, e);
+        Self::respond(&mut buf_stream, "500 Internal Server Error", "");
+        return;
+      }
+    };
+    if local_path != file_path {
+      eprintln!("Local path of {:?}: {:?}", &file_path, &local_path);
+    }
 
     if let Err(e) = buf_stream
       .write_all(b"HTTP/1.1 200 OK\r\n\r\n")
       .and_then(|_| buf_stream.flush())
     {
-      println!("Write response header failed: {}", e);
+      eprintln!("Write response header failed: {}", e);
       return;
     }
-    println!("Recv {:?} from {} done", file_path, peer_addr);
+    eprintln!("Recv {:?} from {} done ({})", local_path, peer_addr, digest);
+    Self::count_uploads(1);
   }
 
-  fn recv(key: &str, socket: SocketAddr, reserve: bool, proxy: Option<ProxyConsumer>, auto_rename: bool) {
-    let (pub_addr, proxy_master_socket) = if let Some(proxy) = &proxy {
-      (proxy.public_socket, Some(proxy.master_stream.peer_addr().unwrap()))
+  /// Receive every file of a `multipart/form-data` upload (e.g. a plain HTML form) as it streams in, fields without a
+  /// file name are ignored, the response lists the outcome of each file
+  fn handle_multipart(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], boundary: &str, peer_addr: SocketAddr, reserve: bool,
+    auto_rename: bool, output_dir: &Path,
+  ) -> u64 {
+    let Some(content_length) = Self::content_length(buf_stream, headers, peer_addr) else {
+      return 0;
+    };
+    if !Self::send_continue(buf_stream) {
+      return 0;
+    }
+    let pb = match content_length {
+      Some(content_length) => Self::progress_bar(content_length),
+      None => Self::spinner(),
+    };
+    let body = match content_length {
+      Some(content_length) => BodyReader::Sized((&mut *buf_stream).take(content_length)),
+      None => BodyReader::Chunked(ChunkedReader::new(&mut *buf_stream)),
+    };
+    let mut multipart = MultipartReader::new(pb.wrap_read(body), boundary);
+    let mut results = vec![];
+    let mut malformed = false;
+    loop {
+      let part_headers = match multipart.next_part() {
+        Ok(Some(part_headers)) => part_headers,
+        Ok(None) => break,
+        Err(e) => {
+          eprintln!("Read multipart body from {} failed: {}", peer_addr, e);
+          malformed = true;
+          break;
+        }
+      };
+      let name = match http::header(&part_headers, "Content-Disposition")
+        .and_then(http::content_disposition_filename)
+        .filter(|name| !name.is_empty())
+      {
+        Some(name) => name.to_string(),
+        None => continue,
+      };
+      match Self::recv_part(&mut multipart, &name, peer_addr, reserve, auto_rename, output_dir) {
+        Ok((local_path, size, digest)) => {
+          eprintln!("Recv {:?} from {} done ({})", local_path, peer_addr, digest);
+          results.push(PartResult {
+            name,
+            path: Some(local_path.to_string_lossy().into_owned()),
+            size,
+            sha256: Some(digest::to_hex(&digest.sha256)),
+            error: None,
+          });
+        }
+        Err(e) => {
+          eprintln!("Recv {:?} from {} failed: {}", name, peer_addr, e);
+          results.push(PartResult {
+            name,
+            path: None,
+            size: 0,
+            sha256: None,
+            error: Some(e),
+          });
+        }
+      }
+    }
+    drop(multipart);
+    pb.finish();
+
+    let status = if malformed || results.is_empty() || results.iter().any(|result| result.error.is_some()) {
+      "400 Bad Request"
     } else {
-      (socket, None)
+      "200 OK"
     };
+    let (content_type, body) =
+      if http::header(headers, "Accept").is_some_and(|value| value.contains("application/json")) {
+        ("application/json", serde_json::to_vec(&results).unwrap_or_default())
+      } else {
+        let mut body = String::new();
+        for result in &results {
+          match (&result.path, &result.sha256, &result.error) {
+            (Some(path), Some(sha256), _) => body.push_str(&format!(
+              "OK {} -> {} ({} bytes, sha-256 {})\n",
+              result.name, path, result.size, sha256
+            )),
+            (_, _, error) => body.push_str(&format!(
+              "FAILED {}: {}\n",
+              result.name,
+              error.as_deref().unwrap_or_default()
+            )),
+          }
+        }
+        if malformed {
+          body.push_str("FAILED: malformed multipart body\n");
+        } else if results.is_empty() {
+          body.push_str("FAILED: no files in the form\n");
+        }
+        ("text/plain;charset=utf-8", body.into_bytes())
+      };
+    if let Err(e) = buf_stream
+      .write_all(
+        format!(
+          "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+          body.len()
+        )
+        .as_bytes(),
+      )
+      .and_then(|_| buf_stream.write_all(&body))
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response failed: {}", e);
+    }
+    results.iter().filter(|result| result.error.is_none()).count() as u64
+  }
 
-    let key_cloned = key.to_string();
-    if let Err(e) = ctrlc::set_handler(move || {
-      if let Some(socket) = proxy_master_socket {
-        ProxyMaster::end_proxy(&key_cloned, socket);
-      }
-      process::exit(0);
+  /// Write the content of one multipart part through its part file, returning the local path, size and digest
+  fn recv_part<R: Read>(
+    reader: &mut R, name: &str, peer_addr: SocketAddr, reserve: bool, auto_rename: bool, output_dir: &Path,
+  ) -> Result<(PathBuf, u64, Digest), String> {
+    let file_path = Self::to_local_path(name, reserve, output_dir)?;
+    eprintln!("\nRecving {:?} from {}", &file_path, peer_addr);
+    if let Some(folder) = file_path.parent()
+      && !folder.exists()
+    {
+      fs::create_dir_all(folder).map_err(|e| format!("create folder failed: {}", e))?;
+    }
+    let part_path = Self::get_part_path(&file_path);
+    let mut writer = HashWriter::new(BufWriter::new(
+      File::create(&part_path).map_err(|e| format!("create output file failed: {}", e))?,
+    ));
+    let (size, digest) = match io::copy(reader, &mut writer).and_then(|size| {
+      let (file_writer, digest) = writer.finish();
+      file_writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+      Ok((size, digest))
     }) {
-      println!("Set Ctrl-C handler failed: {}", e);
+      Ok(received) => received,
+      Err(e) => {
+        if let Err(e) = fs::remove_file(&part_path) {
+          eprintln!("Remove partial file {:?} failed: {}", part_path, e);
+        }
+        return Err(format!("receive failed: {}", e));
+      }
+    };
+    let local_path =
+      Self::commit_part(&part_path, &file_path, auto_rename).map_err(|e| format!("move into place failed: {}", e))?;
+    Ok((local_path, size, digest))
+  }
+
+  /// The length of a request body, `Some(None)` for chunked bodies of unknown length, `None` if it is missing or
+  /// malformed
+  fn content_length(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], peer_addr: SocketAddr,
+  ) -> Option<Option<u64>> {
+    match http::header(headers, "Content-Length").map(|value| value.parse::<u64>()) {
+      _ if http::is_chunked(headers) => Some(None),
+      Some(Ok(content_length)) => Some(Some(content_length)),
+      Some(Err(e)) => {
+        eprintln!("Parse content length from header failed: {}", e);
+        None
+      }
+      None => {
+        eprintln!("Missing content length from {}", peer_addr);
+        Self::respond(buf_stream, "411 Length Required", "");
+        None
+      }
+    }
+  }
+
+  /// Write the body of a single upload to stdout and exit once it is complete, it cannot be resumed
+  fn handle_stdout(buf_stream: &mut BufStream<Stream>, headers: &[String], is_head: bool, peer_addr: SocketAddr) {
+    if is_head {
+      Self::respond(buf_stream, "200 OK", "Upload-Offset: 0\r\n");
+      return;
+    }
+    if http::header(headers, "Content-Type")
+      .and_then(http::multipart_boundary)
+      .is_some()
+    {
+      eprintln!("Multipart upload from {} cannot be written to stdout", peer_addr);
+      Self::respond(buf_stream, "415 Unsupported Media Type", "");
+      return;
+    }
+    if let Some(value) = http::header(headers, "Content-Range")
+      && !matches!(http::parse_content_range(value), Some((0, end, total)) if end + 1 == total)
+    {
+      eprintln!("Partial upload from {} cannot be written to stdout", peer_addr);
+      Self::respond(buf_stream, "416 Range Not Satisfiable", "Upload-Offset: 0\r\n");
+      return;
+    }
+    let encoding = http::header(headers, "Content-Encoding")
+      .map(|value| value.to_lowercase())
+      .filter(|value| value != "identity");
+    if let Some(encoding) = &encoding
+      && !matches!(encoding.as_str(), "gzip" | "x-gzip" | "zstd")
+    {
+      eprintln!("Unsupported content encoding from {}: {}", peer_addr, encoding);
+      Self::respond(
+        buf_stream,
+        "415 Unsupported Media Type",
+        "Accept-Encoding: gzip, zstd\r\n",
+      );
+      return;
+    }
+    let mut checksums = match Checksum::from_headers(headers) {
+      Ok(checksums) => checksums,
+      Err(e) => {
+        eprintln!("Bad checksum from {}: {}", peer_addr, e);
+        Self::respond(buf_stream, "400 Bad Request", "");
+        return;
+      }
+    };
+    let Some(content_length) = Self::content_length(buf_stream, headers, peer_addr) else {
+      return;
+    };
+    {
+      let mut taken = STDOUT_TAKEN.lock().unwrap();
+      if *taken {
+        eprintln!("An upload is written to stdout already, rejected {}", peer_addr);
+        Self::respond(buf_stream, "409 Conflict", "");
+        return;
+      }
+      *taken = true;
+    }
+    if !Self::send_continue(buf_stream) {
+      *STDOUT_TAKEN.lock().unwrap() = false;
       return;
     }
 
-    println!();
-    let default_cmd = format!(
-      "for f in <FILES>; do curl -X POST -H \"File-Path: $f\" -T $f http://{}/{}; done",
-      pub_addr, key
-    );
-    print!("\x1B]52;c;{}\x07", general_purpose::STANDARD.encode(&default_cmd));
-    println!("cURL (Bash): {}", default_cmd);
-    println!(
-      "cURL (PowerShell): foreach ($f in \"f1\", \"f2\") {{ curl -X POST -H \"File-Path: $f\" -T $f http://{}/{} }}",
-      pub_addr, key
-    );
-    println!(
-      "cURL (CMD): FOR %f IN (f1, f2) DO curl -X POST -H \"File-Path: %f\" -T %f http://{}/{}",
-      pub_addr, key
+    eprintln!("\nRecving stdout from {}", peer_addr);
+    let pb = match content_length {
+      Some(content_length) => Self::progress_bar(content_length),
+      None => Self::spinner(),
+    };
+    let mut hasher = Hasher::for_checksums(&checksums);
+    if content_length.is_none() && http::header(headers, "Trailer").is_some() {
+      hasher = hasher.with_md5();
+    }
+    let mut body = match content_length {
+      Some(content_length) => BodyReader::Sized((&mut *buf_stream).take(content_length)),
+      None => BodyReader::Chunked(ChunkedReader::new(&mut *buf_stream)),
+    };
+    let mut stdout = io::stdout().lock();
+    let result = Self::decode_into(pb.wrap_read(&mut body), encoding.as_deref(), &mut stdout, &mut hasher)
+      .and_then(|_| match &body {
+        BodyReader::Sized(reader) if reader.limit() > 0 => Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          "connection closed before upload completed",
+        )),
+        _ => Ok(()),
+      })
+      .and_then(|_| stdout.flush());
+    pb.finish();
+    let trailer_checksums = Checksum::from_headers(body.trailers());
+    drop(body);
+    if let Err(e) = result {
+      eprintln!("Write upload from {} to stdout failed: {}", peer_addr, e);
+      Self::respond(buf_stream, "400 Bad Request", "");
+      Self::shutdown(1);
+    }
+    match trailer_checksums {
+      Ok(trailer_checksums) => checksums.extend(trailer_checksums),
+      Err(e) => {
+        eprintln!("Bad checksum trailer from {}: {}", peer_addr, e);
+        Self::respond(buf_stream, "400 Bad Request", "");
+        Self::shutdown(1);
+      }
+    }
+    let digest = hasher.finalize();
+    if let Err(e) = digest.verify(&checksums) {
+      eprintln!("Verify upload from {} failed: {}", peer_addr, e);
+      Self::respond(buf_stream, "422 Unprocessable Entity", "");
+      Self::shutdown(1);
+    }
+    Self::respond(buf_stream, "200 OK", "");
+    eprintln!("Recv stdout from {} done ({})", peer_addr, digest);
+    Self::shutdown(0);
+  }
+
+  /// The exit code when stopped before reaching the upload limit, without a limit any received file counts as success
+  fn exit_code() -> i32 {
+    match *UPLOADS.lock().unwrap() {
+      (uploads, None) if uploads > 0 => 0,
+      _ => 2,
+    }
+  }
+
+  /// Leave the proxy if one is used and exit
+  fn shutdown(code: i32) -> ! {
+    ProxyMaster::leave();
+    process::exit(code);
+  }
+
+  /// Decompress a `Content-Encoding: gzip/zstd` body into `writer`, or copy it as is without an encoding, checksums of
+  /// encoded uploads cover the decoded file
+  fn decode_into<R: Read, W: Write>(
+    reader: R, encoding: Option<&str>, writer: &mut W, hasher: &mut Hasher,
+  ) -> io::Result<()> {
+    let mut decoder: Box<dyn Read> = match encoding {
+      Some("zstd") => Box::new(zstd::Decoder::new(reader)?),
+      Some(_) => Box::new(MultiGzDecoder::new(reader)),
+      None => Box::new(reader),
+    };
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+      let n = decoder.read(&mut buf)?;
+      if n == 0 {
+        return Ok(());
+      }
+      writer.write_all(&buf[..n])?;
+      hasher.update(&buf[..n]);
+    }
+  }
+
+  /// Progress of a transfer of unknown size
+  pub fn spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})").unwrap());
+    pb
+  }
+
+  pub fn progress_bar(total: u64) -> ProgressBar {
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+      ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+          write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+        })
+        .progress_chars("#>-"),
     );
+    pb
+  }
+
+  /// Receive an encrypted tar archive from an `ncp put` client
+  fn handle_secure_recv(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], key: &str, peer_addr: SocketAddr, reserve: bool,
+    auto_rename: bool, output_dir: &Path,
+  ) {
+    let Some(keys) = secure::accept(buf_stream, headers, key, peer_addr) else {
+      return;
+    };
+    if !headers[0].trim().starts_with(&format!("POST /{} HTTP/", key))
+      && !headers[0].trim().starts_with(&format!("PUT /{} HTTP/", key))
+    {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+      Self::respond(buf_stream, "400 Bad Request", "");
+      return;
+    }
+    if !Self::send_continue(buf_stream) {
+      return;
+    }
+
+    eprintln!("\nRecving from {} (end-to-end encrypted)", peer_addr);
+    // `ncp put` streams the archive chunked, clients of older versions send its length
+    let body: Box<dyn Read> = if http::is_chunked(headers) {
+      Box::new(ChunkedReader::new(&mut *buf_stream))
+    } else {
+      Box::new(&mut *buf_stream)
+    };
+    let mut archive = Archive::new(keys.reader(body));
+    let mut saved = 0;
+    let result = archive
+      .entries()
+      .and_then(|entries| {
+        for entry in entries {
+          if Self::unpack_entry(entry?, peer_addr, reserve, auto_rename, output_dir)? {
+            saved += 1;
+          }
+        }
+        Ok(())
+      })
+      // Read up to the last frame, so that a truncated stream is reported
+      .and_then(|_| io::copy(&mut archive.into_inner(), &mut io::sink()));
+    match result {
+      Ok(_) => {
+        Self::respond(buf_stream, "200 OK", "");
+        eprintln!("Recv from {} done", peer_addr);
+        Self::count_uploads(saved);
+      }
+      Err(e) => {
+        eprintln!("Recv from {} failed: {}", peer_addr, e);
+        Self::respond(buf_stream, "400 Bad Request", "");
+      }
+    }
+  }
+
+  /// Save a regular file of the received archive through a part file, other entries are skipped. Returns whether a
+  /// file was saved
+  fn unpack_entry<R: Read>(
+    mut entry: Entry<R>, peer_addr: SocketAddr, reserve: bool, auto_rename: bool, output_dir: &Path,
+  ) -> io::Result<bool> {
+    let path = entry.path()?.to_string_lossy().to_string();
+    if !entry.header().entry_type().is_file() {
+      return Ok(false);
+    }
+    let file_path = Self::to_local_path(&path, reserve, output_dir).map_err(|e| {
+      io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("rejected file path {:?}: {}", path, e),
+      )
+    })?;
+    if let Some(folder) = file_path.parent() {
+      fs::create_dir_all(folder)?;
+    }
+    let part_path = Self::get_part_path(&file_path);
+    let mut file = File::create(&part_path)?;
+    io::copy(&mut entry, &mut file)?;
+    file.sync_all()?;
+    let local_path = Self::commit_part(&part_path, &file_path, auto_rename)?;
+    eprintln!("Recv {:?} from {}", local_path, peer_addr);
+    Ok(true)
+  }
+
+  /// Let the client send the body once the request has been checked, so that a rejection comes before it
+  fn send_continue<S: Read + Write>(buf_stream: &mut BufStream<S>) -> bool {
+    if let Err(e) = buf_stream
+      .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response header failed: {}", e);
+      return false;
+    }
+    true
+  }
+
+  fn respond<S: Read + Write>(buf_stream: &mut BufStream<S>, status: &str, headers: &str) {
+    if let Err(e) = buf_stream
+      .write_all(format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\n\r\n").as_bytes())
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response header failed: {}", e);
+    }
+  }
+
+  /// The hidden file in the target directory that holds data until the upload completes
+  pub fn get_part_path(file_path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(file_path.file_name().unwrap_or_default());
+    file_name.push(".ncp-part");
+    file_path.with_file_name(file_name)
+  }
+
+  fn get_auto_rename_path(file_path: &Path, i: usize) -> PathBuf {
+    if i == 0 {
+      file_path.to_path_buf()
+    } else if file_path.extension().is_none() {
+      file_path.with_file_name(format!("{}-{}", file_path.file_stem().unwrap().to_str().unwrap(), i))
+    } else {
+      file_path.with_file_name(format!(
+        "{}-{}.{}",
+        file_path.file_stem().unwrap().to_str().unwrap(),
+        i,
+        file_path.extension().unwrap().to_str().unwrap(),
+      ))
+    }
+  }
+
+  /// Move a completed part file into place, the previous version of `file_path` is replaced atomically, or with
+  /// `auto_rename` the first free name is claimed by a hard link so that nothing is ever clobbered
+  pub fn commit_part(part_path: &Path, file_path: &Path, auto_rename: bool) -> io::Result<PathBuf> {
+    let mut local_path = file_path.to_path_buf();
+    if auto_rename {
+      let mut i = 0;
+      loop {
+        local_path = Self::get_auto_rename_path(file_path, i);
+        match fs::hard_link(part_path, &local_path) {
+          Ok(_) => {
+            fs::remove_file(part_path)?;
+            break;
+          }
+          Err(e) if e.kind() == io::ErrorKind::AlreadyExists => i += 1,
+          // Hard links are not supported by every file system
+          Err(_) if !local_path.exists() => {
+            fs::rename(part_path, &local_path)?;
+            break;
+          }
+          Err(e) => return Err(e),
+        }
+      }
+    } else {
+      fs::rename(part_path, &local_path)?;
+    }
+    // Persist the rename itself, opening a directory fails on Windows where this is not needed
+    if let Some(parent) = local_path.parent() {
+      let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+      } else {
+        parent
+      };
+      if let Ok(dir) = File::open(parent) {
+        dir.sync_all().ok();
+      }
+    }
+    Ok(local_path)
+  }
+
+  fn recv(
+    key: &str, socket: SocketAddr, reserve: bool, proxy: Option<ProxyConsumer>, auto_rename: bool, output_dir: PathBuf,
+    tls: Option<Arc<Tls>>,
+  ) {
+    if !output_dir.as_os_str().is_empty() && !*TO_STDOUT.lock().unwrap() {
+      if let Err(e) = fs::create_dir_all(&output_dir) {
+        eprintln!("Create output directory {:?} failed: {}", output_dir, e);
+        return;
+      }
+      eprintln!("Received files will be saved into {:?}", output_dir);
+    }
+
+    let (pub_addrs, tls_info) = if let Some(proxy) = &proxy {
+      (vec![proxy.public_socket], proxy.tls.clone())
+    } else {
+      (iface::public_addrs(socket), tls.as_ref().map(|tls| tls.info.clone()))
+    };
+    let (scheme, curl_args, ncp_args) = match &tls_info {
+      Some(tls_info) => ("https", tls_info.curl_args(), tls_info.ncp_args()),
+      None => ("http", String::new(), String::new()),
+    };
+
+    if let Err(e) = ctrlc::set_handler(|| Self::shutdown(Self::exit_code())) {
+      eprintln!("Set Ctrl-C handler failed: {}", e);
+      return;
+    }
+
+    eprintln!();
+    if let Some(tls_info) = &tls_info {
+      tls_info.print();
+    }
+    for (i, pub_addr) in pub_addrs.iter().enumerate() {
+      if pub_addrs.len() > 1 {
+        if i > 0 {
+          eprintln!();
+        }
+        eprintln!("Via {}{}:", pub_addr, if i == 0 { " (recommended)" } else { "" });
+      }
+      let default_cmd;
+      if let Some(code) = secure::code(*pub_addr, key) {
+        default_cmd = format!("ncp put {} <FILES>", code);
+        eprintln!("Code: {}", code);
+        eprintln!("ncp: {}", default_cmd);
+      } else {
+        let url = format!("{}://{}/{}", scheme, pub_addr, key);
+        default_cmd = format!(
+          "for f in <FILES>; do \
+        o=$(curl {0}-sI -H \"File-Path: $f\" {1} | awk 'tolower($1)==\"upload-offset:\"{{print $2+0}}'); \
+        curl {0}-C ${{o:-0}} -X POST -H \"File-Path: $f\" -T $f {1}; done",
+          curl_args, url
+        );
+        eprintln!("cURL (Bash): {}", default_cmd);
+        eprintln!(
+          "cURL (PowerShell): foreach ($f in \"f1\", \"f2\") {{ curl {}-X POST -H \"File-Path: $f\" -T $f {} }}",
+          curl_args, url
+        );
+        eprintln!(
+          "cURL (CMD): FOR %f IN (f1, f2) DO curl {}-X POST -H \"File-Path: %f\" -T %f {}",
+          curl_args, url
+        );
+        eprintln!("ncp: ncp put {}{} <FILES>", ncp_args, url);
+      }
+      if i == 0 {
+        eprint!("\x1B]52;c;{}\x07", general_purpose::STANDARD.encode(&default_cmd));
+      }
+    }
+
+    if let Some(expire) = *EXPIRE.lock().unwrap() {
+      thread::spawn(move || {
+        thread::sleep(expire);
+        eprintln!("\nExpired after {:?}, stopping", expire);
+        drain::stop();
+        Self::shutdown(Self::exit_code());
+      });
+    }
 
     if let Some(proxy) = proxy {
-      let proxy_master_socket = proxy.master_stream.peer_addr().unwrap();
-      for stream in ProxyMaster::get_transport_stream(&key, proxy.master_stream) {
+      for stream in ProxyMaster::get_transport_stream(proxy) {
         let key = key.to_string();
-        thread::spawn(move || Self::handle_recv(stream, &key, reserve, auto_rename));
+        let output_dir = output_dir.clone();
+        thread::spawn(move || {
+          let Some(_guard) = drain::enter() else {
+            return;
+          };
+          Self::handle_recv(stream, &key, reserve, auto_rename, &output_dir);
+        });
       }
-      ProxyMaster::end_proxy(&key, proxy_master_socket);
+      ProxyMaster::leave();
     } else {
-      let listener = match TcpListener::bind(&socket) {
+      let listener = match iface::listen(socket) {
         Ok(listener) => listener,
         Err(e) => {
-          println!("Bind TCP socket to {} failed: {}", socket, e);
+          eprintln!("Bind TCP socket to {} failed: {}", socket, e);
           return;
         }
       };
@@ -295,10 +1085,20 @@ impl Recv {
         match stream {
           Ok(stream) => {
             let key = key.to_string();
-            thread::spawn(move || Self::handle_recv(stream, &key, reserve, auto_rename));
+            let output_dir = output_dir.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+              let Some(_guard) = drain::enter() else {
+                return;
+              };
+              match Stream::new(stream, tls.as_deref()) {
+                Ok(stream) => Self::handle_recv(stream, &key, reserve, auto_rename, &output_dir),
+                Err(e) => eprintln!("{}", e),
+              }
+            });
           }
           Err(e) => {
-            println!("Get incoming stream failed: {}", e);
+            eprintln!("Get incoming stream failed: {}", e);
             continue;
           }
         }
@@ -306,3 +1106,89 @@ impl Recv {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An existing directory to confine paths to, the checks resolve it
+  fn root(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("ncp-test-{}-{}", name, process::id()));
+    fs::create_dir_all(&root).unwrap();
+    root
+  }
+
+  #[test]
+  fn keeps_only_the_file_name_without_reserve() {
+    let root = root("plain");
+    assert_eq!(Recv::to_local_path("a/b/c.txt", false, &root), Ok(root.join("c.txt")));
+    assert_eq!(
+      Recv::to_local_path("../../etc/passwd", false, &root),
+      Ok(root.join("passwd"))
+    );
+    assert_eq!(
+      Recv::to_local_path("/etc/passwd", false, &root),
+      Ok(root.join("passwd"))
+    );
+    assert_eq!(
+      Recv::to_local_path("C:\\Users\\me\\c.txt", false, &root),
+      Ok(root.join("c.txt"))
+    );
+    assert!(Recv::to_local_path("a/..", false, &root).is_err());
+  }
+
+  #[test]
+  fn keeps_relative_directories_with_reserve() {
+    let root = root("reserve");
+    assert_eq!(
+      Recv::to_local_path("a/b/c.txt", true, &root),
+      Ok(root.join("a/b/c.txt"))
+    );
+    assert_eq!(
+      Recv::to_local_path("a\\b\\c.txt", true, &root),
+      Ok(root.join("a/b/c.txt"))
+    );
+    assert_eq!(
+      Recv::to_local_path("./a//b/./c.txt", true, &root),
+      Ok(root.join("a/b/c.txt"))
+    );
+  }
+
+  #[test]
+  fn rejects_parent_directories() {
+    let root = root("parent");
+    assert!(Recv::to_local_path("../c.txt", true, &root).is_err());
+    assert!(Recv::to_local_path("a/../../c.txt", true, &root).is_err());
+    assert!(Recv::to_local_path("a\\..\\..\\c.txt", true, &root).is_err());
+  }
+
+  #[test]
+  fn rejects_absolute_paths_and_drive_prefixes() {
+    let root = root("absolute");
+    assert!(Recv::to_local_path("/etc/passwd", true, &root).is_err());
+    assert!(Recv::to_local_path("\\\\server\\share\\c.txt", true, &root).is_err());
+    assert!(Recv::to_local_path("C:\\Windows\\c.txt", true, &root).is_err());
+    assert!(Recv::to_local_path("C:c.txt", true, &root).is_err());
+  }
+
+  #[test]
+  fn rejects_empty_paths_and_control_characters() {
+    let root = root("empty");
+    for reserve in [false, true] {
+      assert!(Recv::to_local_path("", reserve, &root).is_err());
+      assert!(Recv::to_local_path("//./", reserve, &root).is_err());
+      assert!(Recv::to_local_path("a/\nb", reserve, &root).is_err());
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn rejects_symlinks_out_of_the_root() {
+    let root = root("symlink");
+    let link = root.join("out");
+    if fs::symlink_metadata(&link).is_err() {
+      std::os::unix::fs::symlink(env::temp_dir(), &link).unwrap();
+    }
+    assert!(Recv::to_local_path("out/c.txt", true, &root).is_err());
+  }
+}
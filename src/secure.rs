@@ -0,0 +1,350 @@
+use std::{
+  io::{self, Read, Write},
+  net::SocketAddr,
+  sync::Mutex,
+};
+
+use base64::{Engine as _, engine::general_purpose};
+use bufstream::BufStream;
+use chacha20poly1305::{
+  ChaCha20Poly1305, Key, KeyInit, Nonce,
+  aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use rand::distr::{Alphanumeric, SampleString};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::http;
+
+/// Unconfirmed handshakes allowed before the code is burnt, each of them is a guess of the password
+const MAX_ATTEMPTS: usize = 3;
+/// Plaintext size of a full frame
+const FRAME_SIZE: usize = 64 * 1024;
+/// Bytes added to each frame: length prefix, flag and AEAD tag
+const FRAME_OVERHEAD: u64 = 4 + 1 + 16;
+const IDENTITY: &[u8] = b"net-copy";
+
+static PASSWORD: Mutex<Option<String>> = Mutex::new(None);
+static SESSIONS: Mutex<Vec<(String, Keys)>> = Mutex::new(vec![]);
+static ATTEMPTS: Mutex<usize> = Mutex::new(0);
+
+/// Keys derived from the SPAKE2 shared secret
+#[derive(Clone)]
+pub struct Keys {
+  payload: [u8; 32],
+  server_confirm: [u8; 32],
+  client_confirm: [u8; 32],
+}
+
+impl Keys {
+  fn derive(secret: &[u8]) -> Self {
+    let hkdf = Hkdf::<Sha256>::new(None, secret);
+    let mut keys = Self {
+      payload: [0; 32],
+      server_confirm: [0; 32],
+      client_confirm: [0; 32],
+    };
+    hkdf.expand(b"ncp payload", &mut keys.payload).unwrap();
+    hkdf.expand(b"ncp server confirm", &mut keys.server_confirm).unwrap();
+    hkdf.expand(b"ncp client confirm", &mut keys.client_confirm).unwrap();
+    keys
+  }
+
+  pub fn writer<W: Write>(&self, inner: W) -> SecureWriter<W> {
+    SecureWriter::new(inner, &self.payload)
+  }
+
+  pub fn reader<R: Read>(&self, inner: R) -> SecureReader<R> {
+    SecureReader::new(inner, &self.payload)
+  }
+}
+
+/// Session established by `Client::handshake`, to be sent along with the following request
+pub struct Session {
+  pub id: String,
+  pub keys: Keys,
+}
+
+impl Session {
+  /// Request header lines that authenticate this session
+  pub fn to_headers(&self) -> String {
+    format!(
+      "Ncp-Session: {}\r\nNcp-Confirm: {}\r\n",
+      self.id,
+      general_purpose::STANDARD.encode(self.keys.client_confirm)
+    )
+  }
+}
+
+/// Require ncp clients to prove knowledge of a random password before anything is transferred
+pub fn enable() {
+  *PASSWORD.lock().unwrap() = Some(Alphanumeric.sample_string(&mut rand::rng(), 8));
+}
+
+pub fn is_enabled() -> bool {
+  PASSWORD.lock().unwrap().is_some()
+}
+
+/// The code to hand over to the peer, i.e. `<host>:<port>/<key>-<password>`
+pub fn code(pub_addr: SocketAddr, key: &str) -> Option<String> {
+  PASSWORD
+    .lock()
+    .unwrap()
+    .as_ref()
+    .map(|password| format!("{}/{}-{}", pub_addr, key, password))
+}
+
+/// Split a code into the address, the key and the password
+pub fn parse_code(code: &str) -> Option<(&str, &str, &str)> {
+  let (addr, rest) = code.trim().split_once('/')?;
+  let (key, password) = rest.rsplit_once('-')?;
+  if addr.is_empty() || key.is_empty() || password.is_empty() {
+    None
+  } else {
+    Some((addr, key, password))
+  }
+}
+
+/// Start a handshake as client, returns the state and the message to send
+pub fn start(password: &str) -> (Spake2<Ed25519Group>, String) {
+  let (state, message) = Spake2::<Ed25519Group>::start_symmetric(&Password::new(password), &Identity::new(IDENTITY));
+  (state, general_purpose::STANDARD.encode(message))
+}
+
+/// Finish a handshake as client with the response headers of the server
+pub fn finish(state: Spake2<Ed25519Group>, headers: &[String]) -> Result<Session, String> {
+  let (Some(message), Some(id), Some(confirm)) = (
+    http::header(headers, "Ncp-Pake"),
+    http::header(headers, "Ncp-Session"),
+    http::header(headers, "Ncp-Confirm"),
+  ) else {
+    return Err("missing handshake headers in response".to_string());
+  };
+  let message = general_purpose::STANDARD
+    .decode(message)
+    .map_err(|e| format!("decode handshake message failed: {}", e))?;
+  let secret = state
+    .finish(&message)
+    .map_err(|e| format!("finish handshake failed: {}", e))?;
+  let keys = Keys::derive(&secret);
+  match general_purpose::STANDARD.decode(confirm) {
+    Ok(confirm) if constant_time_eq(&confirm, &keys.server_confirm) => Ok(Session {
+      id: id.to_string(),
+      keys,
+    }),
+    _ => Err("the code is wrong".to_string()),
+  }
+}
+
+/// Serve a request from an ncp client: `POST /<key>/pake` runs the handshake and is answered here, other requests
+/// must carry a session established by it, whose keys are returned (a session can only be used once)
+pub fn accept<S: Read + Write>(
+  buf_stream: &mut BufStream<S>, headers: &[String], key: &str, peer_addr: SocketAddr,
+) -> Option<Keys> {
+  if headers[0].trim().starts_with(&format!("POST /{}/pake HTTP/", key)) {
+    match handshake(headers) {
+      Ok(response_headers) => respond(buf_stream, "200 OK", &response_headers),
+      Err(e) => {
+        eprintln!("Handshake with {} failed: {}", peer_addr, e);
+        respond(buf_stream, "403 Forbidden", "");
+      }
+    }
+    return None;
+  }
+  let session = http::header(headers, "Ncp-Session").and_then(|id| {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let index = sessions.iter().position(|(session_id, _)| session_id == id)?;
+    Some(sessions.remove(index).1)
+  });
+  let confirm = http::header(headers, "Ncp-Confirm").and_then(|value| general_purpose::STANDARD.decode(value).ok());
+  match (session, confirm) {
+    (Some(keys), Some(confirm)) if constant_time_eq(&confirm, &keys.client_confirm) => {
+      *ATTEMPTS.lock().unwrap() -= 1;
+      Some(keys)
+    }
+    (Some(_), _) => {
+      eprintln!("Wrong code from {}", peer_addr);
+      respond(buf_stream, "403 Forbidden", "");
+      None
+    }
+    (None, _) => {
+      eprintln!(
+        "Request without a valid session from {}: {}",
+        peer_addr,
+        headers[0].trim()
+      );
+      respond(buf_stream, "403 Forbidden", "");
+      None
+    }
+  }
+}
+
+fn handshake(headers: &[String]) -> Result<String, String> {
+  let Some(password) = PASSWORD.lock().unwrap().clone() else {
+    return Err("secure mode is not enabled".to_string());
+  };
+  {
+    let mut attempts = ATTEMPTS.lock().unwrap();
+    if *attempts >= MAX_ATTEMPTS {
+      return Err("too many failed attempts, the code has been burnt".to_string());
+    }
+    *attempts += 1;
+  }
+  let message = http::header(headers, "Ncp-Pake")
+    .ok_or_else(|| "missing Ncp-Pake header".to_string())
+    .and_then(|value| {
+      general_purpose::STANDARD
+        .decode(value)
+        .map_err(|e| format!("decode handshake message failed: {}", e))
+    })?;
+  let (state, response) = Spake2::<Ed25519Group>::start_symmetric(&Password::new(password), &Identity::new(IDENTITY));
+  let secret = state
+    .finish(&message)
+    .map_err(|e| format!("finish handshake failed: {}", e))?;
+  let keys = Keys::derive(&secret);
+  let id = Alphanumeric.sample_string(&mut rand::rng(), 16);
+  let headers = format!(
+    "Ncp-Pake: {}\r\nNcp-Session: {}\r\nNcp-Confirm: {}\r\n",
+    general_purpose::STANDARD.encode(response),
+    id,
+    general_purpose::STANDARD.encode(keys.server_confirm)
+  );
+  SESSIONS.lock().unwrap().push((id, keys));
+  Ok(headers)
+}
+
+fn respond<S: Read + Write>(buf_stream: &mut BufStream<S>, status: &str, headers: &str) {
+  if let Err(e) = buf_stream
+    .write_all(format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\n\r\n").as_bytes())
+    .and_then(|_| buf_stream.flush())
+  {
+    eprintln!("Write response header failed: {}", e);
+  }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn nonce(counter: u64) -> Nonce {
+  let mut nonce = [0u8; 12];
+  nonce[4..].copy_from_slice(&counter.to_be_bytes());
+  nonce.into()
+}
+
+/// Writer that encrypts everything into length-prefixed ChaCha20-Poly1305 frames, the last frame is flagged so that
+/// truncation is detected
+pub struct SecureWriter<W: Write> {
+  inner: W,
+  cipher: ChaCha20Poly1305,
+  counter: u64,
+  buf: Vec<u8>,
+}
+
+impl<W: Write> SecureWriter<W> {
+  fn new(inner: W, key: &[u8; 32]) -> Self {
+    Self {
+      inner,
+      cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+      counter: 0,
+      buf: Vec::with_capacity(FRAME_SIZE),
+    }
+  }
+
+  fn write_frame(&mut self, last: bool) -> io::Result<()> {
+    let mut plaintext = Vec::with_capacity(self.buf.len() + 1);
+    plaintext.push(last as u8);
+    plaintext.append(&mut self.buf);
+    let ciphertext = self
+      .cipher
+      .encrypt(&nonce(self.counter), Payload::from(plaintext.as_slice()))
+      .map_err(|_| io::Error::other("encrypt frame failed"))?;
+    self.counter += 1;
+    self.inner.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+    self.inner.write_all(&ciphertext)
+  }
+
+  /// Write the last frame and return the underlying writer
+  pub fn finish(mut self) -> io::Result<W> {
+    self.write_frame(true)?;
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for SecureWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = buf.len().min(FRAME_SIZE - self.buf.len());
+    self.buf.extend_from_slice(&buf[..n]);
+    if self.buf.len() == FRAME_SIZE {
+      self.write_frame(false)?;
+    }
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Reader of the frames written by `SecureWriter`, fails on tampered, reordered or truncated frames
+pub struct SecureReader<R: Read> {
+  inner: R,
+  cipher: ChaCha20Poly1305,
+  counter: u64,
+  buf: Vec<u8>,
+  pos: usize,
+  done: bool,
+}
+
+impl<R: Read> SecureReader<R> {
+  fn new(inner: R, key: &[u8; 32]) -> Self {
+    Self {
+      inner,
+      cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+      counter: 0,
+      buf: vec![],
+      pos: 0,
+      done: false,
+    }
+  }
+
+  fn read_frame(&mut self) -> io::Result<()> {
+    let mut len = [0u8; 4];
+    self.inner.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > FRAME_SIZE + FRAME_OVERHEAD as usize {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "frame is too large"));
+    }
+    let mut ciphertext = vec![0u8; len];
+    self.inner.read_exact(&mut ciphertext)?;
+    let mut plaintext = self
+      .cipher
+      .decrypt(&nonce(self.counter), Payload::from(ciphertext.as_slice()))
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decrypt frame failed"))?;
+    self.counter += 1;
+    if plaintext.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "empty frame"));
+    }
+    self.done = plaintext.remove(0) != 0;
+    self.buf = plaintext;
+    self.pos = 0;
+    Ok(())
+  }
+}
+
+impl<R: Read> Read for SecureReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.pos == self.buf.len() {
+      if self.done {
+        return Ok(0);
+      }
+      self.read_frame()?;
+    }
+    let n = buf.len().min(self.buf.len() - self.pos);
+    buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
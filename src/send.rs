@@ -1,82 +1,157 @@
 use std::{
-  fs::{self, File},
-  io::{BufRead, BufReader, Read, Write},
-  net::{SocketAddr, TcpListener, TcpStream},
-  path::{Path, PathBuf},
+  fs::{self, File, Metadata},
+  io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+  net::SocketAddr,
+  path::{Component, Path, PathBuf},
   process,
-  sync::Mutex,
+  sync::{Arc, Mutex},
   thread,
+  time::{Duration, UNIX_EPOCH},
 };
 
 use base64::{Engine as _, engine::general_purpose};
+use brotli::CompressorWriter;
 use bufstream::BufStream;
+use flate2::{Compression, write::GzEncoder};
 use mime_guess;
+use rand::distr::{Alphanumeric, SampleString};
+use serde_derive::Serialize;
 use tar::Builder;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
 
-use crate::proxy::{ProxyConsumer, ProxyMaster};
+use crate::{
+  config::ArchiveFormat,
+  digest::{self, Digest, HashWriter, Hasher},
+  drain,
+  http::{self, ByteRange, ChunkedWriter},
+  iface,
+  proxy::{ProxyConsumer, ProxyMaster},
+  secure,
+  stream::Stream,
+  tls::Tls,
+};
 
 static FILE_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+static SCRATCH_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+static FILE_DIGEST: Mutex<Option<(String, Digest)>> = Mutex::new(None);
+static ARCHIVE_FORMAT: Mutex<ArchiveFormat> = Mutex::new(ArchiveFormat::Tar);
+/// The file name of the data sent from stdin when `-` is sent
+static STDIN_NAME: Mutex<Option<String>> = Mutex::new(None);
+/// Whether stdin has been claimed by a client already, it cannot be replayed
+static STDIN_TAKEN: Mutex<bool> = Mutex::new(false);
+/// Complete downloads so far and the limit of them, read together under one lock
+static DOWNLOADS: Mutex<(u64, Option<u64>)> = Mutex::new((0, None));
+static EXPIRE: Mutex<Option<Duration>> = Mutex::new(None);
+/// Zip archives being staged in the scratch directory, they are removed on shutdown as well
+static STAGED: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+/// An item of a browsed directory listing
+#[derive(Serialize)]
+struct Entry {
+  name: String,
+  #[serde(rename = "type")]
+  kind: &'static str,
+  size: u64,
+  mtime: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  mime: Option<String>,
+}
 
 pub struct Send {}
 
 impl Send {
-  pub fn run(key: &str, socket: SocketAddr, proxy: Option<ProxyConsumer>, file_paths: Vec<PathBuf>) {
+  pub fn run(
+    key: &str, socket: SocketAddr, proxy: Option<ProxyConsumer>, file_paths: Vec<PathBuf>, scratch_dir: PathBuf,
+    archive_format: ArchiveFormat, tls: Option<Arc<Tls>>,
+  ) {
     FILE_PATHS.lock().unwrap().clear();
     for file_path in file_paths {
       FILE_PATHS.lock().unwrap().push(file_path);
     }
-    Self::send(&key, socket, proxy);
+    if let Err(e) = fs::create_dir_all(&scratch_dir) {
+      eprintln!("Create scratch directory {:?} failed: {}", scratch_dir, e);
+      return;
+    }
+    *SCRATCH_DIR.lock().unwrap() = Some(scratch_dir);
+    *ARCHIVE_FORMAT.lock().unwrap() = archive_format;
+    Self::send(key, socket, proxy, tls);
+  }
+
+  /// Send stdin (`-`) instead of files, clients get it as a file called `name`
+  pub fn read_stdin_as(name: String) {
+    *STDIN_NAME.lock().unwrap() = Some(name);
+  }
+
+  /// Stop serving after `max_downloads` complete downloads of the files, or once `expire` has passed
+  pub fn stop_after(max_downloads: Option<u64>, expire: Option<Duration>) {
+    DOWNLOADS.lock().unwrap().1 = max_downloads;
+    *EXPIRE.lock().unwrap() = expire;
+  }
+
+  fn count_download() {
+    let (downloads, max_downloads) = {
+      let mut downloads = DOWNLOADS.lock().unwrap();
+      downloads.0 += 1;
+      *downloads
+    };
+    // Only the download reaching the limit stops the sender, those in flight are waited for
+    if let Some(max_downloads) = max_downloads
+      && downloads == max_downloads
+    {
+      eprintln!("\nReached the limit of {} downloads, stopping", max_downloads);
+      drain::stop();
+      Self::shutdown(0);
+    }
   }
 
   fn is_archive() -> bool {
+    if STDIN_NAME.lock().unwrap().is_some() {
+      return false;
+    }
     let file_paths = &FILE_PATHS.lock().unwrap();
     file_paths.len() > 1 || !file_paths[0].is_file()
   }
 
-  fn get_tar_path(key: &str) -> PathBuf {
-    PathBuf::from(format!("{}.tar", key))
+  /// The name of `file_path` in an archive, paths that cannot be stored as is (e.g. absolute ones) are stored by their
+  /// last component
+  pub fn archive_name(file_path: &Path) -> &Path {
+    if file_path.is_relative() && !file_path.components().any(|c| c == Component::ParentDir) {
+      file_path
+    } else {
+      file_path.file_name().map(Path::new).unwrap_or(file_path)
+    }
   }
 
-  fn tar(tar_path: &Path) -> bool {
-    let mut tar = match File::create(tar_path) {
-      Ok(file) => Builder::new(file),
-      Err(e) => {
-        println!("Create tar file {:?} failed: {}", tar_path, e);
-        return false;
-      }
-    };
-    let file_paths = FILE_PATHS.lock().unwrap();
-    for file_path in file_paths.iter() {
-      if if file_path.is_dir() {
-        tar.append_dir_all(file_path, file_path)
+  /// Stream `file_paths` as a tar archive into `writer`
+  pub fn tar<W: Write>(writer: W, file_paths: &[PathBuf]) -> io::Result<W> {
+    let entries = file_paths
+      .iter()
+      .map(|file_path| (Self::archive_name(file_path).to_path_buf(), file_path.clone()))
+      .collect::<Vec<_>>();
+    Self::tar_named(writer, &entries)
+  }
+
+  /// Stream `entries` (name in the archive and local path) as a tar archive into `writer`
+  fn tar_named<W: Write>(writer: W, entries: &[(PathBuf, PathBuf)]) -> io::Result<W> {
+    let mut tar = Builder::new(writer);
+    for (name, file_path) in entries {
+      if file_path.is_dir() {
+        tar.append_dir_all(name, file_path)
       } else {
-        tar.append_path(file_path)
-      }
-      .is_err()
-      {
-        println!("Append file to tar failed");
-        return false;
+        tar.append_path_with_name(file_path, name)
       }
+      .map_err(|e| io::Error::new(e.kind(), format!("Append {:?} to tar failed: {}", file_path, e)))?;
     }
-    if tar.finish().is_err() {
-      println!("Write tar file failed");
-      false
-    } else {
-      true
-    }
+    tar.into_inner()
   }
 
   fn handle_send(
-    stream: TcpStream, key: &str, file_path: PathBuf, file_name: String, is_archive: bool, mime_type: String,
+    stream: Stream, key: &str, file_path: PathBuf, file_name: String, is_archive: bool, mime_type: String,
   ) {
-    if is_archive && !file_path.is_file() && !Self::tar(&file_path) {
-      println!("Archive sending files failed");
-      return;
-    }
     let peer_addr = match stream.peer_addr() {
       Ok(peer_addr) => peer_addr,
       Err(e) => {
-        println!("Get peer socket failed: {}", e);
+        eprintln!("Get peer socket failed: {}", e);
         return;
       }
     };
@@ -85,7 +160,7 @@ impl Send {
     loop {
       let mut line = String::new();
       if let Err(e) = buf_stream.read_line(&mut line) {
-        println!("Read line from buffer failed: {}", e);
+        eprintln!("Read line from buffer failed: {}", e);
         return;
       }
       if line == "\r\n" {
@@ -93,48 +168,397 @@ impl Send {
       }
       headers.push(line);
       if headers.len() > 100 {
-        println!("Too many request headers from {}", peer_addr);
+        eprintln!("Too many request headers from {}", peer_addr);
         return;
       }
     }
     if headers.is_empty() {
-      println!("Empty request headers from {}", peer_addr);
+      eprintln!("Empty request headers from {}", peer_addr);
       return;
     }
-    if !headers[0].trim().starts_with(&format!("GET /{} HTTP/", key)) {
-      println!("Bad Request from {}: {}", peer_addr, headers[0].trim());
-      if let Err(e) = buf_stream
-        .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-        .and_then(|_| buf_stream.flush())
-      {
-        println!("Write response header failed: {}", e);
+    if secure::is_enabled() {
+      if Self::handle_secure_send(&mut buf_stream, &headers, key, peer_addr, &file_path) {
+        Self::count_download();
       }
       return;
     }
+    if let Some(target) = headers[0]
+      .trim()
+      .strip_prefix(&format!("GET /{}/", key))
+      .and_then(|rest| rest.rsplit_once(" HTTP/"))
+      .map(|(target, _)| target)
+    {
+      Self::handle_browse(&mut buf_stream, &headers, key, target, peer_addr);
+      return;
+    }
+    let Some(query) = headers[0]
+      .trim()
+      .strip_prefix(&format!("GET /{}", key))
+      .and_then(|rest| rest.rsplit_once(" HTTP/"))
+      .map(|(rest, _)| rest)
+      .filter(|rest| rest.is_empty() || rest.starts_with('?'))
+    else {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+      Self::respond(&mut buf_stream, "400 Bad Request", "", b"");
+      return;
+    };
 
-    println!("\nSending {:?} to {}", file_path, peer_addr);
-    let (file_size, mut file_reader) = match File::open(&file_path) {
-      Ok(file) => (
-        match file.metadata() {
-          Ok(metadata) => metadata.len() as usize,
-          Err(e) => {
-            println!("Get file metadata failed: {}", e);
+    let sent = if is_archive {
+      let format = match Self::query_format(query) {
+        Some(format) => match ArchiveFormat::from_name(format) {
+          Some(format) => format,
+          None => {
+            eprintln!("Unsupported format {:?} from {}", format, peer_addr);
+            Self::respond(&mut buf_stream, "400 Bad Request", "", b"");
             return;
           }
         },
-        BufReader::new(file),
-      ),
+        None => *ARCHIVE_FORMAT.lock().unwrap(),
+      };
+      let entries = FILE_PATHS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|file_path| (Self::archive_name(file_path).to_path_buf(), file_path.clone()))
+        .collect::<Vec<_>>();
+      Self::send_archive(&mut buf_stream, &headers, &entries, key, format, peer_addr)
+    } else if STDIN_NAME.lock().unwrap().is_some() {
+      Self::send_stdin(&mut buf_stream, &file_name, &mime_type, peer_addr);
+      return;
+    } else {
+      eprintln!("\nSending {:?} to {}", file_path, peer_addr);
+      let digest_headers = match &*FILE_DIGEST.lock().unwrap() {
+        Some((digest_etag, digest)) if fs::metadata(&file_path).is_ok_and(|m| *digest_etag == Self::get_etag(&m)) => {
+          digest.to_headers()
+        }
+        _ => String::new(),
+      };
+      Self::send_file(
+        &mut buf_stream,
+        &headers,
+        &file_path,
+        &file_name,
+        &mime_type,
+        &digest_headers,
+        peer_addr,
+      )
+    };
+    if sent {
+      Self::count_download();
+    }
+  }
+
+  /// Send `entries` (name in the archive and local path) as an archive called `stem` plus the extension of `format`.
+  /// Tar archives are streamed with their digest in the trailer, zip ones are staged in the scratch directory first
+  fn send_archive(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], entries: &[(PathBuf, PathBuf)], stem: &str,
+    format: ArchiveFormat, peer_addr: SocketAddr,
+  ) -> bool {
+    let file_name = format!("{}.{}", stem, format.extension());
+    let mime_type = format.mime_type();
+    if format == ArchiveFormat::Zip {
+      let scratch_dir = SCRATCH_DIR.lock().unwrap().clone().unwrap_or_default();
+      let zip_path = scratch_dir.join(format!(
+        ".{}-{}.zip",
+        stem,
+        Alphanumeric.sample_string(&mut rand::rng(), 6)
+      ));
+      eprintln!("\nStaging {:?} in {:?}", file_name, zip_path);
+      STAGED.lock().unwrap().push(zip_path.clone());
+      let result = File::create(&zip_path)
+        .and_then(|file| Self::zip_named(file, entries))
+        .and_then(|_| {
+          let mut hasher = Hasher::new();
+          io::copy(&mut File::open(&zip_path)?, &mut hasher)?;
+          Ok(hasher.finalize())
+        });
+      let sent = match result {
+        Ok(digest) => {
+          eprintln!("Sending {:?} to {}", file_name, peer_addr);
+          Self::send_file(
+            buf_stream,
+            headers,
+            &zip_path,
+            &file_name,
+            mime_type,
+            &digest.to_headers(),
+            peer_addr,
+          )
+        }
+        Err(e) => {
+          eprintln!("Stage {:?} failed: {}", zip_path, e);
+          Self::respond(buf_stream, "500 Internal Server Error", "", b"");
+          false
+        }
+      };
+      Self::unstage(&zip_path);
+      return sent;
+    }
+
+    eprintln!("\nSending {:?} to {}", file_name, peer_addr);
+    // `Archive-Format` tells `ncp get` to extract the body, a served file that happens to be a tar is saved as is
+    if let Err(e) = buf_stream
+      .write_all(
+        format!(
+          "HTTP/1.1 200 OK\r\n\
+          Transfer-Encoding: chunked\r\n\
+          Trailer: {}\r\n\
+          Content-Type: {mime_type}\r\n\
+          Content-Disposition: attachment; filename=\"{file_name}\"\r\n\
+          Archive-Format: {}\r\n\
+          \r\n",
+          digest::HEADER_NAMES,
+          format.extension()
+        )
+        .as_bytes(),
+      )
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response header failed: {}", e);
+      return false;
+    }
+    let writer = BufWriter::with_capacity(64 * 1024, HashWriter::new(ChunkedWriter::new(buf_stream)));
+    let result = match format {
+      ArchiveFormat::TarGz => {
+        Self::tar_named(GzEncoder::new(writer, Compression::default()), entries).and_then(|encoder| encoder.finish())
+      }
+      ArchiveFormat::TarZst => zstd::Encoder::new(writer, 0)
+        .and_then(|encoder| Self::tar_named(encoder, entries))
+        .and_then(|encoder| encoder.finish()),
+      _ => Self::tar_named(writer, entries),
+    };
+    match result.and_then(|writer| writer.into_inner().map_err(|e| e.into_error())) {
+      Ok(writer) => {
+        let (writer, digest) = writer.finish();
+        if let Err(e) = writer.finish_with_trailers(&digest.to_headers()) {
+          eprintln!("Write response trailer failed: {}", e);
+          return false;
+        }
+        eprintln!("Send {:?} to {} done ({})", file_name, peer_addr, digest);
+        true
+      }
       Err(e) => {
-        println!("Open file {:?} failed: {}", file_path, e);
+        eprintln!("Stream {} to {} failed: {}", format.extension(), peer_addr, e);
+        false
+      }
+    }
+  }
+
+  /// Stream stdin to the first client and exit once it has been sent, later clients are turned away as it cannot be
+  /// replayed
+  fn send_stdin(buf_stream: &mut BufStream<Stream>, file_name: &str, mime_type: &str, peer_addr: SocketAddr) {
+    // Stdin only counts as taken once the headers reached the client, a request that fails before leaves it for the
+    // next one
+    {
+      let mut taken = STDIN_TAKEN.lock().unwrap();
+      if *taken {
+        eprintln!("Stdin has been sent already, rejected {}", peer_addr);
+        Self::respond(buf_stream, "410 Gone", "", b"");
+        return;
+      }
+      let result = buf_stream
+        .write_all(
+          format!(
+            "HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: {}\r\n\
+            Content-Type: {mime_type}\r\n\
+            Content-Disposition: attachment; filename=\"{file_name}\"\r\n\
+            \r\n",
+            digest::HEADER_NAMES
+          )
+          .as_bytes(),
+        )
+        .and_then(|_| buf_stream.flush());
+      if let Err(e) = result {
+        eprintln!("Send stdin to {} failed: {}", peer_addr, e);
         return;
       }
+      *taken = true;
+    }
+
+    eprintln!("\nSending stdin as {:?} to {}", file_name, peer_addr);
+    let mut writer = BufWriter::with_capacity(64 * 1024, HashWriter::new(ChunkedWriter::new(&mut *buf_stream)));
+    let result = io::copy(&mut io::stdin().lock(), &mut writer)
+      .and_then(|_| writer.into_inner().map_err(|e| e.into_error()))
+      .and_then(|writer| {
+        let (writer, digest) = writer.finish();
+        writer.finish_with_trailers(&digest.to_headers())?;
+        Ok(digest)
+      });
+    match result {
+      Ok(digest) => {
+        eprintln!("Send stdin to {} done ({})", peer_addr, digest);
+        Self::shutdown(0);
+      }
+      Err(e) => {
+        eprintln!("Send stdin to {} failed: {}", peer_addr, e);
+        Self::shutdown(1);
+      }
+    }
+  }
+
+  fn unstage(zip_path: &Path) {
+    STAGED.lock().unwrap().retain(|path| path != zip_path);
+    if let Err(e) = fs::remove_file(zip_path)
+      && e.kind() != io::ErrorKind::NotFound
+    {
+      eprintln!("Remove {:?} failed: {}", zip_path, e);
+    }
+  }
+
+  /// The exit code when stopped before reaching the download limit, without a limit any completed download counts as
+  /// success
+  fn exit_code() -> i32 {
+    match *DOWNLOADS.lock().unwrap() {
+      (downloads, None) if downloads > 0 => 0,
+      _ => 2,
+    }
+  }
+
+  /// Remove staged archives, leave the proxy if one is used and exit
+  fn shutdown(code: i32) -> ! {
+    for zip_path in STAGED.lock().unwrap().drain(..) {
+      if let Err(e) = fs::remove_file(&zip_path)
+        && e.kind() != io::ErrorKind::NotFound
+      {
+        eprintln!("Remove {:?} failed: {}", zip_path, e);
+      }
+    }
+    ProxyMaster::leave();
+    process::exit(code);
+  }
+
+  /// Write `entries` (name in the archive and local path) as a zip archive into `file`, which gets the newest
+  /// modification time of the entries. The zip of the same files has the same bytes, so a restaged archive keeps its
+  /// ETag and interrupted downloads can resume
+  fn zip_named(file: File, entries: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let mut newest = UNIX_EPOCH;
+    let mut stack = entries.iter().rev().cloned().collect::<Vec<_>>();
+    while let Some((name, file_path)) = stack.pop() {
+      let name = name
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+      let metadata = fs::metadata(&file_path)?;
+      newest = newest.max(metadata.modified().unwrap_or(UNIX_EPOCH));
+      let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(metadata.len() >= u32::MAX as u64);
+      if metadata.is_dir() {
+        zip
+          .add_directory(format!("{}/", name), options)
+          .map_err(io::Error::other)?;
+        let mut children = fs::read_dir(&file_path)?
+          .map(|entry| entry.map(|entry| entry.file_name()))
+          .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        for child in children.into_iter().rev() {
+          stack.push((Path::new(&name).join(&child), file_path.join(&child)));
+        }
+      } else {
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(&mut File::open(&file_path)?, &mut zip)
+          .map_err(|e| io::Error::new(e.kind(), format!("Append {:?} to zip failed: {}", file_path, e)))?;
+      }
+    }
+    let file = zip
+      .finish()
+      .map_err(io::Error::other)?
+      .into_inner()
+      .map_err(|e| e.into_error())?;
+    file.set_modified(newest)
+  }
+
+  /// Send a regular file, honouring `Range` and `If-Range` requests
+  fn send_file(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], file_path: &Path, file_name: &str, mime_type: &str,
+    digest_headers: &str, peer_addr: SocketAddr,
+  ) -> bool {
+    let (file_size, etag, mut file_reader) = match File::open(file_path) {
+      Ok(file) => match file.metadata() {
+        Ok(metadata) => (metadata.len(), Self::get_etag(&metadata), BufReader::new(file)),
+        Err(e) => {
+          eprintln!("Get file metadata failed: {}", e);
+          return false;
+        }
+      },
+      Err(e) => {
+        eprintln!("Open file {:?} failed: {}", file_path, e);
+        return false;
+      }
+    };
+    let range = match http::header(headers, "Range") {
+      Some(value) => match http::header(headers, "If-Range") {
+        Some(if_range) if if_range != etag => ByteRange::Full,
+        _ => ByteRange::parse(value, file_size),
+      },
+      None => ByteRange::Full,
+    };
+    let (status, content_range, start, content_length) = match range {
+      ByteRange::Full => ("200 OK", String::new(), 0, file_size),
+      ByteRange::Partial(start, end) => (
+        "206 Partial Content",
+        format!("Content-Range: bytes {}-{}/{}\r\n", start, end, file_size),
+        start,
+        end - start + 1,
+      ),
+      ByteRange::Unsatisfiable => {
+        eprintln!("Range not satisfiable from {}", peer_addr);
+        if let Err(e) = buf_stream
+          .write_all(
+            format!(
+              "HTTP/1.1 416 Range Not Satisfiable\r\n\
+              Content-Range: bytes */{file_size}\r\n\
+              Content-Length: 0\r\n\
+              \r\n"
+            )
+            .as_bytes(),
+          )
+          .and_then(|_| buf_stream.flush())
+        {
+          eprintln!("Write response header failed: {}", e);
+        }
+        return false;
+      }
     };
+    let compressible = Self::is_compressible(mime_type);
+    if let Some(encoding) = http::header(headers, "Accept-Encoding")
+      .filter(|_| compressible && content_length == file_size && file_size > 0)
+      .and_then(|value| http::accept_encoding(value, &["zstd", "br", "gzip"]))
+    {
+      return Self::send_encoded(
+        buf_stream,
+        file_reader,
+        file_path,
+        file_name,
+        mime_type,
+        encoding,
+        peer_addr,
+      );
+    }
+    let vary = if compressible { "Vary: Accept-Encoding\r\n" } else { "" };
+
+    if start > 0 {
+      if let Err(e) = file_reader.seek(SeekFrom::Start(start)) {
+        eprintln!("Seek sending file failed: {}", e);
+        return false;
+      }
+      eprintln!("Resuming from byte {} of {}", start, file_size);
+    }
 
     if let Err(e) = buf_stream
       .write_all(
         format!(
-          "HTTP/1.1 200 OK\r\n\
-          Content-Length: {file_size}\r\n\
+          "HTTP/1.1 {status}\r\n\
+          Content-Length: {content_length}\r\n\
+          {content_range}\
+          Accept-Ranges: bytes\r\n\
+          ETag: {etag}\r\n\
+          {digest_headers}\
+          {vary}\
           Content-Type: {mime_type}\r\n\
           Content-Disposition: attachment; filename=\"{file_name}\"\r\n\
           \r\n"
@@ -143,109 +567,555 @@ impl Send {
       )
       .and_then(|_| buf_stream.flush())
     {
-      println!("Write response header failed: {}", e);
-      return;
+      eprintln!("Write response header failed: {}", e);
+      return false;
     }
 
+    let mut hasher = (content_length == file_size).then(Hasher::new);
     let mut buf = vec![0u8; 16 * 1024];
-    let mut left_size = file_size;
+    let mut left_size = content_length;
     let mut send_size = 0;
     while left_size > 0 {
-      match file_reader.read(&mut buf) {
+      let limit = buf.len().min(left_size.try_into().unwrap_or(usize::MAX));
+      match file_reader.read(&mut buf[..limit]) {
+        Ok(0) => {
+          eprintln!("Sending file {:?} ended unexpectedly", file_path);
+          return false;
+        }
         Ok(n) => {
           if let Err(e) = buf_stream.write_all(&buf[..n]) {
-            println!("Write response content failed: {}", e);
-            return;
+            eprintln!("Write response content failed: {}", e);
+            return false;
           }
-          left_size -= n;
+          if let Some(hasher) = &mut hasher {
+            hasher.update(&buf[..n]);
+          }
+          left_size -= n as u64;
           send_size += n;
           if send_size >= 16 * 1024 * 1024 {
             if let Err(e) = buf_stream.flush() {
-              println!("Flush writer failed: {}", e);
-              return;
+              eprintln!("Flush writer failed: {}", e);
+              return false;
             }
             send_size = 0;
           }
         }
         Err(e) => {
-          println!("Read sending file failed: {}", e);
-          return;
+          eprintln!("Read sending file failed: {}", e);
+          return false;
         }
       }
     }
     if let Err(e) = buf_stream.flush() {
-      println!("Flush stream failed: {}", e);
+      eprintln!("Flush stream failed: {}", e);
+      return false;
+    }
+    match hasher {
+      Some(hasher) => eprintln!("Send {:?} to {} done ({})", file_path, peer_addr, hasher.finalize()),
+      None => eprintln!("Send {:?} to {} done", file_path, peer_addr),
+    }
+    // Only the whole file counts as a download, ranges reaching the end may as well be probes of the last bytes
+    start == 0 && content_length == file_size
+  }
+
+  /// Serve the browsable view under `/<key>/`: directory listings in HTML or JSON (`?format=json`), directories as
+  /// tar archives (`?format=tar`) and individual files at their path relative to the sent ones. Complete files and
+  /// archives count as downloads, listings do not
+  fn handle_browse(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], key: &str, target: &str, peer_addr: SocketAddr,
+  ) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let format = Self::query_format(query);
+    let Some(path) = http::percent_decode(path) else {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+      Self::respond(buf_stream, "400 Bad Request", "", b"");
+      return;
+    };
+    let components = path.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    if components.iter().any(|c| *c == "." || *c == ".." || c.contains('\\')) {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+      Self::respond(buf_stream, "400 Bad Request", "", b"");
+      return;
+    }
+    let local_path = if components.is_empty() {
+      None
+    } else {
+      match Self::resolve(&components) {
+        Some(local_path) => Some(local_path),
+        None => {
+          eprintln!("Not Found from {}: {}", peer_addr, headers[0].trim());
+          Self::respond(buf_stream, "404 Not Found", "", b"");
+          return;
+        }
+      }
+    };
+
+    if let Some(local_path) = &local_path
+      && !local_path.is_dir()
+    {
+      let file_name = components[components.len() - 1];
+      let mime_type = mime_guess::from_path(local_path).first_or_octet_stream().to_string();
+      eprintln!("\nSending {:?} to {}", local_path, peer_addr);
+      if Self::send_file(buf_stream, headers, local_path, file_name, &mime_type, "", peer_addr) {
+        Self::count_download();
+      }
+      return;
+    }
+    if !path.is_empty() && !path.ends_with('/') {
+      let location = format!("Location: /{}/{}/\r\n", key, http::percent_encode(&path));
+      Self::respond(buf_stream, "301 Moved Permanently", &location, b"");
+      return;
+    }
+
+    if let Some(archive_format) = format.and_then(ArchiveFormat::from_name) {
+      let (stem, entries) = match &local_path {
+        Some(local_path) => (
+          components[components.len() - 1].to_string(),
+          vec![(PathBuf::from(components.join("/")), local_path.clone())],
+        ),
+        None => (
+          key.to_string(),
+          FILE_PATHS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|file_path| (Self::archive_name(file_path).to_path_buf(), file_path.clone()))
+            .collect(),
+        ),
+      };
+      if Self::send_archive(buf_stream, headers, &entries, &stem, archive_format, peer_addr) {
+        Self::count_download();
+      }
       return;
     }
-    println!("Send {:?} to {} done", file_path, peer_addr);
+    match format {
+      Some("json") | Some("html") | None => {
+        let entries = match Self::list(local_path.as_deref()) {
+          Ok(entries) => entries,
+          Err(e) => {
+            eprintln!("List {:?} failed: {}", local_path, e);
+            Self::respond(buf_stream, "500 Internal Server Error", "", b"");
+            return;
+          }
+        };
+        if format == Some("json") {
+          match serde_json::to_vec(&entries) {
+            Ok(body) => Self::respond(buf_stream, "200 OK", "Content-Type: application/json\r\n", &body),
+            Err(e) => eprintln!("Serialize listing failed: {}", e),
+          }
+        } else {
+          let body = Self::index_html(&format!("/{}", path), &entries);
+          Self::respond(
+            buf_stream,
+            "200 OK",
+            "Content-Type: text/html;charset=utf-8\r\n",
+            body.as_bytes(),
+          );
+        }
+      }
+      Some(format) => {
+        eprintln!("Unsupported format {:?} from {}", format, peer_addr);
+        Self::respond(buf_stream, "400 Bad Request", "", b"");
+      }
+    }
+  }
+
+  /// The `format` parameter of a query string
+  fn query_format(query: &str) -> Option<&str> {
+    query
+      .trim_start_matches('?')
+      .split('&')
+      .find_map(|param| param.strip_prefix("format="))
+  }
+
+  /// Map the components of a browsed path onto the local file system, through the sent path they start with
+  fn resolve(components: &[&str]) -> Option<PathBuf> {
+    let file_paths = FILE_PATHS.lock().unwrap();
+    file_paths.iter().find_map(|file_path| {
+      let name = Self::archive_name(file_path)
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+      if !components.starts_with(&name) {
+        return None;
+      }
+      let local_path = components[name.len()..]
+        .iter()
+        .fold(file_path.clone(), |local_path, c| local_path.join(c));
+      local_path.exists().then_some(local_path)
+    })
+  }
+
+  /// The entries of the sent directory `local_path`, or of the sent files themselves for `None`
+  fn list(local_path: Option<&Path>) -> io::Result<Vec<Entry>> {
+    let mut items = match local_path {
+      Some(local_path) => fs::read_dir(local_path)?
+        .map(|entry| {
+          let entry = entry?;
+          Ok((entry.file_name().to_string_lossy().to_string(), entry.path()))
+        })
+        .collect::<io::Result<Vec<_>>>()?,
+      None => FILE_PATHS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|file_path| {
+          (
+            Self::archive_name(file_path).to_string_lossy().to_string(),
+            file_path.clone(),
+          )
+        })
+        .collect(),
+    };
+    items.sort();
+    let mut entries = items
+      .into_iter()
+      .filter_map(|(name, path)| {
+        let metadata = fs::metadata(&path).ok()?;
+        let mtime = metadata
+          .modified()
+          .ok()
+          .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+          .map(|d| d.as_secs())
+          .unwrap_or_default();
+        Some(if metadata.is_dir() {
+          Entry {
+            name,
+            kind: "dir",
+            size: 0,
+            mtime,
+            mime: None,
+          }
+        } else {
+          Entry {
+            mime: Some(mime_guess::from_path(&path).first_or_octet_stream().to_string()),
+            name,
+            kind: "file",
+            size: metadata.len(),
+            mtime,
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.kind != "dir");
+    Ok(entries)
+  }
+
+  fn index_html(path: &str, entries: &[Entry]) -> String {
+    let title = http::html_escape(path);
+    let mut rows = String::new();
+    if path != "/" {
+      rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+      let href = http::html_escape(&http::percent_encode(&entry.name));
+      let name = http::html_escape(&entry.name);
+      if entry.kind == "dir" {
+        rows.push_str(&format!(
+          "<tr><td><a href=\"{href}/\">{name}/</a></td><td>-</td><td><a href=\"{href}/?format=tar\">tar</a></td></tr>\n"
+        ));
+      } else {
+        rows.push_str(&format!(
+          "<tr><td><a href=\"{href}\">{name}</a></td><td>{}</td><td></td></tr>\n",
+          entry.size
+        ));
+      }
+    }
+    format!(
+      "<!DOCTYPE html>\n\
+      <html>\n\
+      <head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+      <body>\n\
+      <h1>Index of {title}</h1>\n\
+      <p><a href=\"?format=tar\">Download all as tar</a> | <a href=\"?format=json\">JSON</a></p>\n\
+      <table>\n\
+      <tr><th>Name</th><th>Size</th><th>Archive</th></tr>\n\
+      {rows}\
+      </table>\n\
+      </body>\n\
+      </html>\n"
+    )
+  }
+
+  fn respond(buf_stream: &mut BufStream<Stream>, status: &str, headers: &str, body: &[u8]) {
+    if let Err(e) = buf_stream
+      .write_all(format!("HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+      .and_then(|_| buf_stream.write_all(body))
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response failed: {}", e);
+    }
+  }
+
+  /// Stream a whole file compressed with the negotiated content coding, the digest of the encoded bytes is sent in the
+  /// trailer
+  fn send_encoded(
+    buf_stream: &mut BufStream<Stream>, mut file_reader: BufReader<File>, file_path: &Path, file_name: &str,
+    mime_type: &str, encoding: &str, peer_addr: SocketAddr,
+  ) -> bool {
+    if let Err(e) = buf_stream
+      .write_all(
+        format!(
+          "HTTP/1.1 200 OK\r\n\
+          Transfer-Encoding: chunked\r\n\
+          Trailer: {}\r\n\
+          Content-Encoding: {encoding}\r\n\
+          Vary: Accept-Encoding\r\n\
+          Content-Type: {mime_type}\r\n\
+          Content-Disposition: attachment; filename=\"{file_name}\"\r\n\
+          \r\n",
+          digest::HEADER_NAMES
+        )
+        .as_bytes(),
+      )
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response header failed: {}", e);
+      return false;
+    }
+    let writer = BufWriter::with_capacity(64 * 1024, HashWriter::new(ChunkedWriter::new(buf_stream)));
+    let result = match encoding {
+      "zstd" => zstd::Encoder::new(writer, 0).and_then(|mut encoder| {
+        io::copy(&mut file_reader, &mut encoder)?;
+        encoder.finish()
+      }),
+      "br" => {
+        let mut encoder = CompressorWriter::new(writer, 64 * 1024, 5, 22);
+        io::copy(&mut file_reader, &mut encoder).map(|_| encoder.into_inner())
+      }
+      _ => {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        io::copy(&mut file_reader, &mut encoder).and_then(|_| encoder.finish())
+      }
+    };
+    match result.and_then(|writer| writer.into_inner().map_err(|e| e.into_error())) {
+      Ok(writer) => {
+        let (writer, digest) = writer.finish();
+        if let Err(e) = writer.finish_with_trailers(&digest.to_headers()) {
+          eprintln!("Write response trailer failed: {}", e);
+          return false;
+        }
+        eprintln!(
+          "Send {:?} to {} done ({} encoded, {})",
+          file_path, peer_addr, encoding, digest
+        );
+        true
+      }
+      Err(e) => {
+        eprintln!(
+          "Stream {} encoded {:?} to {} failed: {}",
+          encoding, file_path, peer_addr, e
+        );
+        false
+      }
+    }
+  }
+
+  /// Whether compressing `mime_type` is worth it, formats which are compressed already are not
+  fn is_compressible(mime_type: &str) -> bool {
+    let (kind, subtype) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+    match kind {
+      "text" => true,
+      "image" => matches!(subtype, "svg+xml" | "bmp" | "x-icon" | "vnd.microsoft.icon" | "tiff"),
+      "audio" => matches!(subtype, "wav" | "x-wav"),
+      "video" => false,
+      "font" => !subtype.starts_with("woff"),
+      _ => {
+        !matches!(
+          subtype,
+          "zip"
+            | "gzip"
+            | "x-gzip"
+            | "zstd"
+            | "x-bzip2"
+            | "x-xz"
+            | "x-lzma"
+            | "x-compress"
+            | "x-7z-compressed"
+            | "vnd.rar"
+            | "x-rar-compressed"
+            | "java-archive"
+            | "epub+zip"
+            | "vnd.android.package-archive"
+        ) && !subtype.starts_with("vnd.openxmlformats")
+          && !subtype.starts_with("vnd.oasis.opendocument")
+      }
+    }
+  }
+
+  /// Stream the files as an encrypted tar archive to an `ncp get` client
+  fn handle_secure_send(
+    buf_stream: &mut BufStream<Stream>, headers: &[String], key: &str, peer_addr: SocketAddr, file_path: &Path,
+  ) -> bool {
+    let Some(keys) = secure::accept(buf_stream, headers, key, peer_addr) else {
+      return false;
+    };
+    if !headers[0].trim().starts_with(&format!("GET /{} HTTP/", key)) {
+      eprintln!("Bad Request from {}: {}", peer_addr, headers[0].trim());
+      if let Err(e) = buf_stream
+        .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+        .and_then(|_| buf_stream.flush())
+      {
+        eprintln!("Write response header failed: {}", e);
+      }
+      return false;
+    }
+
+    eprintln!("\nSending {:?} to {} (end-to-end encrypted)", file_path, peer_addr);
+    if let Err(e) = buf_stream
+      .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n")
+      .and_then(|_| buf_stream.flush())
+    {
+      eprintln!("Write response header failed: {}", e);
+      return false;
+    }
+    let file_paths = FILE_PATHS.lock().unwrap().clone();
+    match Self::tar(keys.writer(buf_stream), &file_paths).and_then(|writer| writer.finish()) {
+      Ok(_) => {
+        eprintln!("Send {:?} to {} done", file_path, peer_addr);
+        true
+      }
+      Err(e) => {
+        eprintln!("Stream encrypted tar to {} failed: {}", peer_addr, e);
+        false
+      }
+    }
+  }
+
+  fn get_etag(metadata: &Metadata) -> String {
+    let mtime = metadata
+      .modified()
+      .ok()
+      .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+      .map(|d| d.as_secs())
+      .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime)
+  }
+
+  /// Hash the single sending file in background, so that responses can carry its digest once ready
+  fn digest_file(file_path: PathBuf) {
+    thread::spawn(move || {
+      let result = File::open(&file_path).and_then(|mut file| {
+        let etag = Self::get_etag(&file.metadata()?);
+        let mut hasher = Hasher::new();
+        io::copy(&mut file, &mut hasher)?;
+        Ok((etag, hasher.finalize()))
+      });
+      match result {
+        Ok((etag, digest)) => {
+          eprintln!("Digest of {:?}: {}", file_path, digest);
+          *FILE_DIGEST.lock().unwrap() = Some((etag, digest));
+        }
+        Err(e) => eprintln!("Compute digest of {:?} failed: {}", file_path, e),
+      }
+    });
   }
 
-  fn send(key: &str, socket: SocketAddr, proxy: Option<ProxyConsumer>) {
+  fn send(key: &str, socket: SocketAddr, proxy: Option<ProxyConsumer>, tls: Option<Arc<Tls>>) {
     let is_archive = Self::is_archive();
+    let stdin_name = STDIN_NAME.lock().unwrap().clone();
     let file_path = if is_archive {
-      let file_path = Self::get_tar_path(key);
-      if !file_path.is_file() {
-        Self::tar(&file_path);
-      }
-      file_path
+      PathBuf::from(format!("{}.{}", key, ARCHIVE_FORMAT.lock().unwrap().extension()))
+    } else if let Some(stdin_name) = &stdin_name {
+      PathBuf::from(stdin_name)
     } else {
       FILE_PATHS.lock().unwrap()[0].clone()
     };
     let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
     let mime_type = mime_guess::from_path(&file_path).first_or_octet_stream().to_string();
+    if !is_archive && stdin_name.is_none() {
+      Self::digest_file(file_path.clone());
+    }
+    let archive_format = *ARCHIVE_FORMAT.lock().unwrap();
 
-    let (pub_addr, proxy_master_socket) = if let Some(proxy) = &proxy {
-      (proxy.public_socket, Some(proxy.master_stream.peer_addr().unwrap()))
+    let (pub_addrs, tls_info) = if let Some(proxy) = &proxy {
+      (vec![proxy.public_socket], proxy.tls.clone())
     } else {
-      (socket, None)
+      (iface::public_addrs(socket), tls.as_ref().map(|tls| tls.info.clone()))
+    };
+    let (scheme, curl_args, wget_args, ncp_args) = match &tls_info {
+      Some(tls_info) => ("https", tls_info.curl_args(), tls_info.wget_args(), tls_info.ncp_args()),
+      None => ("http", String::new(), String::new(), String::new()),
     };
 
-    let tar_path = file_path.clone();
-    let key_cloned = key.to_string();
-    if let Err(e) = ctrlc::set_handler(move || {
-      if let Some(socket) = proxy_master_socket {
-        ProxyMaster::end_proxy(&key_cloned, socket);
+    if let Err(e) = ctrlc::set_handler(|| Self::shutdown(Self::exit_code())) {
+      eprintln!("Set Ctrl-C handler failed: {}", e);
+      return;
+    }
+
+    eprintln!();
+    if let Some(tls_info) = &tls_info {
+      tls_info.print();
+    }
+    for (i, pub_addr) in pub_addrs.iter().enumerate() {
+      if pub_addrs.len() > 1 {
+        if i > 0 {
+          eprintln!();
+        }
+        eprintln!("Via {}{}:", pub_addr, if i == 0 { " (recommended)" } else { "" });
       }
-      if is_archive && tar_path.is_file() {
-        fs::remove_file(&tar_path).expect("Remove tar file failed");
+      let default_cmd;
+      if let Some(code) = secure::code(*pub_addr, key) {
+        default_cmd = format!("ncp get {}", code);
+        eprintln!("Code: {}", code);
+        eprintln!("ncp: {}", default_cmd);
+      } else if let Some(extract_cmd) = archive_format.extract_cmd().filter(|_| is_archive) {
+        default_cmd = format!("curl {}{}://{}/{} | {}", curl_args, scheme, pub_addr, key, extract_cmd);
+        eprintln!("cURL: {}", default_cmd);
+        eprintln!(
+          "Wget: wget {}-O- {}://{}/{} | {}",
+          wget_args, scheme, pub_addr, key, extract_cmd
+        );
+      } else {
+        default_cmd = format!(
+          "curl {}-o \"{}\" {}://{}/{}",
+          curl_args, file_name, scheme, pub_addr, key
+        );
+        eprintln!("cURL: {}", default_cmd);
+        eprintln!(
+          "Wget: wget {}-O \"{}\" {}://{}/{}",
+          wget_args, file_name, scheme, pub_addr, key
+        );
+      }
+      if !secure::is_enabled() {
+        if is_archive {
+          eprintln!("Index: {}://{}/{}/", scheme, pub_addr, key);
+        }
+        eprintln!("ncp: ncp get {}{}://{}/{}", ncp_args, scheme, pub_addr, key);
+      }
+      if i == 0 {
+        eprint!("\x1B]52;c;{}\x07", general_purpose::STANDARD.encode(&default_cmd));
       }
-      process::exit(0);
-    }) {
-      println!("Set Ctrl-C handler failed: {}", e);
-      return;
     }
 
-    println!();
-    let default_cmd;
-    if is_archive {
-      default_cmd = format!("curl http://{}/{} | tar xvf -", pub_addr, key);
-      print!("\x1B]52;c;{}\x07", general_purpose::STANDARD.encode(&default_cmd));
-      println!("cURL: {}", default_cmd);
-      println!("Wget: wget -O- http://{}/{} | tar xvf -", pub_addr, key);
-    } else {
-      default_cmd = format!("curl -o \"{}\" http://{}/{}", file_name, pub_addr, key);
-      print!("\x1B]52;c;{}\x07", general_purpose::STANDARD.encode(&default_cmd));
-      println!("cURL: {}", default_cmd);
-      println!("Wget: wget -O \"{}\" http://{}/{}", file_name, pub_addr, key);
+    if let Some(expire) = *EXPIRE.lock().unwrap() {
+      thread::spawn(move || {
+        thread::sleep(expire);
+        eprintln!("\nExpired after {:?}, stopping", expire);
+        drain::stop();
+        Self::shutdown(Self::exit_code());
+      });
     }
 
     if let Some(proxy) = proxy {
-      let proxy_master_socket = proxy.master_stream.peer_addr().unwrap();
-      for stream in ProxyMaster::get_transport_stream(key, proxy.master_stream) {
+      for stream in ProxyMaster::get_transport_stream(proxy) {
         let key = key.to_string();
         let file_path = file_path.clone();
         let file_name = file_name.clone();
         let mime_type = mime_type.clone();
         thread::spawn(move || {
+          let Some(_guard) = drain::enter() else {
+            return;
+          };
           Self::handle_send(stream, &key, file_path, file_name, is_archive, mime_type);
         });
       }
-      ProxyMaster::end_proxy(&key, proxy_master_socket);
+      ProxyMaster::leave();
     } else {
-      let listener = match TcpListener::bind(socket) {
+      let listener = match iface::listen(socket) {
         Ok(listener) => listener,
         Err(e) => {
-          println!("Bind TCP socket to {} failed: {}", socket, e);
+          eprintln!("Bind TCP socket to {} failed: {}", socket, e);
           return;
         }
       };
@@ -256,12 +1126,19 @@ impl Send {
             let file_path = file_path.clone();
             let file_name = file_name.clone();
             let mime_type = mime_type.clone();
+            let tls = tls.clone();
             thread::spawn(move || {
-              Self::handle_send(stream, &key, file_path, file_name, is_archive, mime_type);
+              let Some(_guard) = drain::enter() else {
+                return;
+              };
+              match Stream::new(stream, tls.as_deref()) {
+                Ok(stream) => Self::handle_send(stream, &key, file_path, file_name, is_archive, mime_type),
+                Err(e) => eprintln!("{}", e),
+              }
             });
           }
           Err(e) => {
-            println!("Get incoming stream failed: {}", e);
+            eprintln!("Get incoming stream failed: {}", e);
             continue;
           }
         }
@@ -0,0 +1,72 @@
+use std::{
+  io::{self, Read, Write},
+  net::{SocketAddr, TcpStream},
+};
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+
+use crate::{mux::MuxStream, rate, tls::Tls};
+
+/// Connection served by the sender, the receiver or the proxy listener, or opened by `ncp get`/`ncp put`, its transfer
+/// rate is limited by `rate`
+pub enum Stream {
+  Tcp(TcpStream),
+  Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+  TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+  /// Multiplexed over the connection to the proxy master
+  Mux(MuxStream),
+}
+
+impl Stream {
+  /// Wrap an accepted connection with TLS if enabled
+  pub fn new(stream: TcpStream, tls: Option<&Tls>) -> Result<Self, String> {
+    match tls {
+      Some(tls) => tls.wrap(stream),
+      None => Ok(Self::Tcp(stream)),
+    }
+  }
+
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+    match self {
+      Self::Tcp(stream) => stream.peer_addr(),
+      Self::Tls(stream) => stream.sock.peer_addr(),
+      Self::TlsClient(stream) => stream.sock.peer_addr(),
+      Self::Mux(stream) => stream.peer_addr(),
+    }
+  }
+}
+
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = match self {
+      Self::Tcp(stream) => stream.read(buf),
+      Self::Tls(stream) => stream.read(buf),
+      Self::TlsClient(stream) => stream.read(buf),
+      Self::Mux(stream) => stream.read(buf),
+    }?;
+    rate::throttle(n);
+    Ok(n)
+  }
+}
+
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = match self {
+      Self::Tcp(stream) => stream.write(buf),
+      Self::Tls(stream) => stream.write(buf),
+      Self::TlsClient(stream) => stream.write(buf),
+      Self::Mux(stream) => stream.write(buf),
+    }?;
+    rate::throttle(n);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Self::Tcp(stream) => stream.flush(),
+      Self::Tls(stream) => stream.flush(),
+      Self::TlsClient(stream) => stream.flush(),
+      Self::Mux(stream) => stream.flush(),
+    }
+  }
+}
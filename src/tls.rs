@@ -0,0 +1,181 @@
+use std::{net::TcpStream, path::Path, sync::Arc};
+
+use base64::{Engine as _, engine::general_purpose};
+use rcgen::PublicKeyData;
+use rustls::{
+  ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, ServerConnection, SignatureScheme, StreamOwned,
+  client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+  crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+  pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest as _, Sha256};
+
+use crate::stream::Stream;
+
+/// What a client needs to know for verifying the server certificate
+#[derive(Clone)]
+pub struct TlsInfo {
+  /// SHA-256 fingerprint of the certificate
+  pub fingerprint: String,
+  /// `sha256//<base64>` of the public key, only for self-signed certificates
+  pub pinned_pubkey: Option<String>,
+}
+
+impl TlsInfo {
+  pub fn curl_args(&self) -> String {
+    match &self.pinned_pubkey {
+      Some(pin) => format!("-k --pinnedpubkey \"{}\" ", pin),
+      None => String::new(),
+    }
+  }
+
+  pub fn ncp_args(&self) -> String {
+    format!("--fingerprint {} ", self.fingerprint)
+  }
+
+  pub fn wget_args(&self) -> String {
+    match &self.pinned_pubkey {
+      Some(pin) => format!("--no-check-certificate --pinnedpubkey={} ", pin),
+      None => String::new(),
+    }
+  }
+
+  pub fn print(&self) {
+    eprintln!("TLS certificate fingerprint (SHA-256): {}", self.fingerprint);
+    if let Some(pin) = &self.pinned_pubkey {
+      eprintln!("TLS public key pin: {}", pin);
+    }
+  }
+}
+
+pub struct Tls {
+  config: Arc<ServerConfig>,
+  pub info: TlsInfo,
+}
+
+impl Tls {
+  /// Load the certificate chain and private key from PEM files, or generate an ephemeral self-signed certificate
+  pub fn new(cert_path: Option<&Path>, key_path: Option<&Path>) -> Result<Self, String> {
+    let (certs, key, pinned_pubkey) = match (cert_path, key_path) {
+      (Some(cert_path), Some(key_path)) => {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+          .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+          .map_err(|e| format!("Load TLS certificate {:?} failed: {}", cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+          .map_err(|e| format!("Load TLS private key {:?} failed: {}", key_path, e))?;
+        (certs, key, None)
+      }
+      (None, None) => {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+          .map_err(|e| format!("Generate self-signed certificate failed: {}", e))?;
+        let pin = format!(
+          "sha256//{}",
+          general_purpose::STANDARD.encode(Sha256::digest(certified.signing_key.subject_public_key_info()))
+        );
+        let key = PrivateKeyDer::try_from(certified.signing_key.serialize_der())
+          .map_err(|e| format!("Serialize private key failed: {}", e))?;
+        (vec![certified.cert.der().clone()], key, Some(pin))
+      }
+      _ => return Err("Both TLS certificate and private key are required".to_string()),
+    };
+    let Some(cert) = certs.first() else {
+      return Err("No certificate found".to_string());
+    };
+    let fingerprint = get_fingerprint(cert);
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .map_err(|e| format!("Create TLS config failed: {}", e))?
+      .with_no_client_auth()
+      .with_single_cert(certs, key)
+      .map_err(|e| format!("Create TLS config failed: {}", e))?;
+    Ok(Self {
+      config: Arc::new(config),
+      info: TlsInfo {
+        fingerprint,
+        pinned_pubkey,
+      },
+    })
+  }
+
+  /// Wrap an accepted connection, the handshake happens on first read or write
+  pub fn wrap(&self, stream: TcpStream) -> Result<Stream, String> {
+    let conn =
+      ServerConnection::new(self.config.clone()).map_err(|e| format!("Create TLS connection failed: {}", e))?;
+    Ok(Stream::Tls(Box::new(StreamOwned::new(conn, stream))))
+  }
+
+  /// Connect to an HTTPS server, its certificate must match `fingerprint` unless `insecure`
+  pub fn connect(stream: TcpStream, host: &str, fingerprint: Option<&str>, insecure: bool) -> Result<Stream, String> {
+    if fingerprint.is_none() && !insecure {
+      return Err("The server certificate cannot be verified, please specify --fingerprint or --insecure".to_string());
+    }
+    let provider = Arc::new(ring::default_provider());
+    let verifier = FingerprintVerifier {
+      fingerprint: fingerprint.map(normalize_fingerprint),
+      provider: provider.clone(),
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()
+      .map_err(|e| format!("Create TLS config failed: {}", e))?
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(verifier))
+      .with_no_client_auth();
+    let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string())
+      .map_err(|e| format!("Bad server name {:?}: {}", host, e))?;
+    let conn = ClientConnection::new(Arc::new(config), server_name)
+      .map_err(|e| format!("Create TLS connection failed: {}", e))?;
+    Ok(Stream::TlsClient(Box::new(StreamOwned::new(conn, stream))))
+  }
+}
+
+fn get_fingerprint(cert: &[u8]) -> String {
+  Sha256::digest(cert)
+    .iter()
+    .map(|b| format!("{:02X}", b))
+    .collect::<Vec<_>>()
+    .join(":")
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+  fingerprint.replace(':', "").to_uppercase()
+}
+
+/// Trust the server certificate by its SHA-256 fingerprint instead of a CA, no fingerprint means trust anything
+#[derive(Debug)]
+struct FingerprintVerifier {
+  fingerprint: Option<String>,
+  provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+  fn verify_server_cert(
+    &self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8], _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    match &self.fingerprint {
+      Some(fingerprint) if *fingerprint != normalize_fingerprint(&get_fingerprint(end_entity)) => {
+        Err(rustls::Error::General(format!(
+          "certificate fingerprint {} mismatch",
+          get_fingerprint(end_entity)
+        )))
+      }
+      _ => Ok(ServerCertVerified::assertion()),
+    }
+  }
+
+  fn verify_tls12_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.provider.signature_verification_algorithms.supported_schemes()
+  }
+}